@@ -25,13 +25,20 @@ use std::{
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
-use zxspectrum_raze::{self as raze, Game, Model};
+use zxspectrum_raze::{self as raze, BorderSize, Game, Model, PsgChip, SpeakerFilter, StereoMode};
 
 struct App {
     redock: bool,
     game: raze::Game<GameUi>,
     gui: GameUi,
     cursor_mode: CursorMode,
+    border_size: BorderSize,
+    ulaplus: bool,
+    snow: bool,
+    speaker_filter: SpeakerFilter,
+    stereo_mode: StereoMode,
+    psg_chip: PsgChip,
+    turbo_sound: bool,
     turbo: bool,
     pause: bool,
     fullscreen: bool,
@@ -98,6 +105,7 @@ struct GameUi {
     texture: Texture,
     size: Cell<Vector2>,
     audio_buffer: Arc<Mutex<AudioBuffer>>,
+    sample_rate: u32,
     do_sound_ft: bool,
     plan_ft: fftw::plan::R2CPlan32,
     audio_ft: Vec<f32>,
@@ -111,11 +119,10 @@ struct AudioBuffer {
     data: VecDeque<AudioBlock>,
 }
 
+// The samples of a frame: (48000 samples/s) / 50 (frames / s) = 960 samples, approx.
+// They are stereo, so twice that values, interleaved.
 struct AudioBlock {
-    // (22050 samples/s) / 50 (frames / s) = 440 samples / frame, approx.
-    // round up to 500
-    data: [f32; 500],
-    length: usize,
+    data: Vec<f32>,
 }
 
 const AUDIO_FT_BLOCK: usize = 430;
@@ -137,12 +144,7 @@ impl Default for AudioBlock {
 
 impl From<&[f32]> for AudioBlock {
     fn from(src: &[f32]) -> Self {
-        let mut res = AudioBlock {
-            data: [0.0; 500],
-            length: src.len(),
-        };
-        res.data[..res.length].copy_from_slice(src);
-        res
+        AudioBlock { data: src.to_vec() }
     }
 }
 
@@ -177,14 +179,14 @@ impl AudioBuffer {
     }
     #[allow(dead_code)]
     fn len(&self) -> usize {
-        self.data.iter().map(|d| d.length).sum::<usize>() - self.offset
+        self.data.iter().map(|d| d.data.len()).sum::<usize>() - self.offset
     }
 }
 
 impl<I: std::slice::SliceIndex<[f32]>> std::ops::Index<I> for AudioBlock {
     type Output = I::Output;
     fn index(&self, index: I) -> &I::Output {
-        &self.data[..self.length][index]
+        &self.data[index]
     }
 }
 
@@ -236,6 +238,10 @@ impl raze::Gui for GameUi {
         ],
     ];
 
+    fn rgb(r: u8, g: u8, b: u8) -> [u8; 3] {
+        [r, g, b]
+    }
+
     fn on_rzx_running(&mut self, _running: bool, _percent: u32) {}
 
     fn on_tape_block(&mut self, _index: usize) {}
@@ -243,12 +249,13 @@ impl raze::Gui for GameUi {
     fn put_sound_data(&mut self, data: &[f32]) {
         let mut ab = self.audio_buffer.lock().unwrap();
         ab.data.push_back(AudioBlock::from(data));
-        if self.do_sound_ft
-            && let Some(data) = data.get(0..AUDIO_FT_BLOCK)
-        {
-            let mut fdata = fftw::array::AlignedVec::new(data.len());
-            fdata.copy_from_slice(data);
-            let mut ft = fftw::array::AlignedVec::new(data.len() / 2 + 1);
+        // The spectrum of the left channel only
+        if self.do_sound_ft && data.len() >= 2 * AUDIO_FT_BLOCK {
+            let mut fdata = fftw::array::AlignedVec::new(AUDIO_FT_BLOCK);
+            for (f, d) in fdata.iter_mut().zip(data.iter().step_by(2)) {
+                *f = *d;
+            }
+            let mut ft = fftw::array::AlignedVec::new(AUDIO_FT_BLOCK / 2 + 1);
             self.plan_ft.r2c(&mut fdata, &mut ft).unwrap();
             self.audio_ft = ft[1..]
                 .iter()
@@ -319,9 +326,14 @@ impl Application for App {
             .expect("no audio output available");
         let mut cfs = snd_dev.supported_output_configs().unwrap();
         let output_config = cfs
-            .find(|c| c.channels() == 1 && c.sample_format() == SampleFormat::F32)
+            .find(|c| c.channels() == 2 && c.sample_format() == SampleFormat::F32)
             .expect("no compatible audio configuration");
-        let output_config = output_config.with_sample_rate(22050);
+        // Any rate will do, the game generates the audio at the rate of the device
+        let sample_rate = 48000.clamp(
+            output_config.min_sample_rate(),
+            output_config.max_sample_rate(),
+        );
+        let output_config = output_config.with_sample_rate(sample_rate);
         let mut snd_cfg = output_config.config();
         snd_cfg.buffer_size = cpal::BufferSize::Fixed(512);
 
@@ -347,6 +359,7 @@ impl Application for App {
             texture,
             size: Cell::new(vec2(4.0, 3.0)),
             audio_buffer,
+            sample_rate,
             do_sound_ft: false,
             plan_ft: fftw::plan::R2CPlan32::aligned(
                 std::slice::from_ref(&AUDIO_FT_BLOCK),
@@ -366,6 +379,13 @@ impl Application for App {
             game,
             gui,
             cursor_mode: CursorMode::CursorKeys,
+            border_size: BorderSize::default(),
+            ulaplus: false,
+            snow: true,
+            speaker_filter: SpeakerFilter::default(),
+            stereo_mode: StereoMode::default(),
+            psg_chip: PsgChip::default(),
+            turbo_sound: false,
             turbo: false,
             pause: false,
             fullscreen: false,
@@ -456,6 +476,16 @@ enum UiAction {
 
 impl UiBuilder for App {
     fn pre_render(&mut self, _ctx: &mut easy_imgui::CurrentContext<'_>) {
+        // Reapplied every frame, because a reset or a snapshot creates a new game
+        self.game.set_border_size(self.border_size);
+        self.game.set_ulaplus(self.ulaplus);
+        self.game.set_sample_rate(self.gui.sample_rate);
+        self.game.set_speaker_filter(self.speaker_filter);
+        self.game.set_stereo_mode(self.stereo_mode);
+        // Before the chip, so that both PSGs get it
+        self.game.set_turbo_sound(self.turbo_sound);
+        self.game.set_psg_chip(self.psg_chip);
+        self.game.set_snow(self.snow);
         if !self.pause {
             while self.gui.audio_buffer.lock().unwrap().data.len() < 3 {
                 self.game.draw_frame(self.turbo, &mut self.gui);
@@ -688,11 +718,41 @@ impl UiBuilder for App {
                     };
                 }
                 ui.same_line();
+                if ui.button(lbl_id("Reset 16K", "reset_16")) {
+                    ui_action = UiAction::Reset {
+                        model: Model::Spec16k,
+                    };
+                }
+                ui.same_line();
+                if ui.button(lbl_id("Reset +2", "reset_plus2")) {
+                    ui_action = UiAction::Reset {
+                        model: Model::Plus2,
+                    };
+                }
+                ui.same_line();
+                if ui.button(lbl_id("Reset +2A", "reset_plus2a")) {
+                    ui_action = UiAction::Reset {
+                        model: Model::Plus2A,
+                    };
+                }
+                ui.same_line();
                 if ui.button(lbl_id("Reset +3", "reset_plus3")) {
                     ui_action = UiAction::Reset {
                         model: Model::Plus3,
                     };
                 }
+                ui.same_line();
+                if ui.button(lbl_id("Reset Pentagon", "reset_pentagon")) {
+                    ui_action = UiAction::Reset {
+                        model: Model::Pentagon,
+                    };
+                }
+                ui.same_line();
+                if ui.button(lbl_id("Reset TC2048", "reset_tc2048")) {
+                    ui_action = UiAction::Reset {
+                        model: Model::Tc2048,
+                    };
+                }
 
                 ui.with_push(
                     if self.pause {
@@ -754,9 +814,67 @@ impl UiBuilder for App {
                     },
                     &mut self.cursor_mode,
                 );
+
+                ui.align_text_to_frame_padding();
+                ui.text("Border");
+                ui.same_line();
+                ui.set_next_item_width(200.0);
+                ui.combo(
+                    lbl_id("", "border_size"),
+                    [
+                        BorderSize::None,
+                        BorderSize::Small,
+                        BorderSize::Normal,
+                        BorderSize::Full,
+                    ],
+                    |bs| match bs {
+                        BorderSize::None => "None",
+                        BorderSize::Small => "Small",
+                        BorderSize::Normal => "Normal",
+                        BorderSize::Full => "Full",
+                    },
+                    &mut self.border_size,
+                );
+                ui.same_line();
+                ui.checkbox(lbl_id("ULAplus", "ulaplus"), &mut self.ulaplus);
+                ui.same_line();
+                ui.checkbox(lbl_id("Snow", "snow"), &mut self.snow);
             });
 
         let maybe_sound = ui.window_config(lbl_id("Sound", "sound")).with(|| {
+            ui.combo(
+                lbl_id("Stereo", "stereo"),
+                [StereoMode::Mono, StereoMode::Abc, StereoMode::Acb],
+                |sm| match sm {
+                    StereoMode::Mono => "Mono",
+                    StereoMode::Abc => "ABC",
+                    StereoMode::Acb => "ACB",
+                    StereoMode::Custom(_) => "Custom",
+                },
+                &mut self.stereo_mode,
+            );
+            ui.combo(
+                lbl_id("Chip", "psg_chip"),
+                [PsgChip::Ay8910, PsgChip::Ym2149],
+                |chip| match chip {
+                    PsgChip::Ay8910 => "AY-3-8910",
+                    PsgChip::Ym2149 => "YM2149",
+                },
+                &mut self.psg_chip,
+            );
+            ui.checkbox(lbl_id("TurboSound", "turbo_sound"), &mut self.turbo_sound);
+            ui.slider_float_config(
+                lbl_id("Low-pass", "low_pass"),
+                &mut self.speaker_filter.low_pass,
+            )
+            .range(1000.0, 20000.0)
+            .build();
+            ui.slider_float_config(
+                lbl_id("High-pass", "high_pass"),
+                &mut self.speaker_filter.high_pass,
+            )
+            .range(0.0, 200.0)
+            .build();
             if !self.gui.audio_ft.is_empty() {
                 unsafe {
                     easy_imgui_sys::ImGui_PlotLines(
@@ -776,7 +894,7 @@ impl UiBuilder for App {
         self.gui.do_sound_ft = maybe_sound.is_some();
 
         ui.window_config(lbl_id("AY-3-8910", "psg")).with(|| {
-            if let Some(d) = self.game.psg_status() {
+            if let Some(d) = self.game.psg_status(0) {
                 let fa = u16::from_le_bytes([d[1], d[2]]);
                 let fb = u16::from_le_bytes([d[3], d[4]]);
                 let fc = u16::from_le_bytes([d[5], d[6]]);
@@ -959,6 +1077,7 @@ impl App {
                     text: String::from("Snapshot files"),
                     globs: vec![
                         glob::Pattern::new("*.z80").unwrap(),
+                        glob::Pattern::new("*.sna").unwrap(),
                         glob::Pattern::new("*.rzx").unwrap(),
                         glob::Pattern::new("*.zip").unwrap(),
                     ],
@@ -0,0 +1,130 @@
+// Breakpoints and watchpoints, to stop the emulation for debugging
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Watch {
+    //Stops before running the instruction at this address
+    Exec(u16),
+    //Stops after the instruction that reads from this address. Opcodes and immediate operands
+    //do not count as reads. With `page`, only if that RAM page (0..7) is mapped there.
+    Read { addr: u16, page: Option<u8> },
+    //Stops after the instruction that writes to this address, same as Read
+    Write { addr: u16, page: Option<u8> },
+    //Stops after the instruction that reads from a port equal to `port` in the bits of `mask`
+    In { port: u16, mask: u16 },
+    //Same as In, but for writes to the port
+    Out { port: u16, mask: u16 },
+}
+
+//Why the execution stopped before the end of the frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    //The PC of the breakpoint, the instruction is not run yet
+    Breakpoint(u16),
+    //Address and value of the memory read or written
+    Read(u16, u8),
+    Write(u16, u8),
+    //Port and value of the IN or OUT
+    In(u16, u8),
+    Out(u16, u8),
+}
+
+#[derive(Default)]
+pub struct Watches {
+    list: Vec<Watch>,
+    //cached kinds of watches in the list, checked in every access
+    exec: bool,
+    mem: bool,
+    io: bool,
+    hit: Option<StopReason>,
+}
+
+impl Watches {
+    pub fn list(&self) -> &[Watch] {
+        &self.list
+    }
+    pub fn add(&mut self, w: Watch) {
+        if !self.list.contains(&w) {
+            self.list.push(w);
+            self.update();
+        }
+    }
+    pub fn remove(&mut self, w: Watch) -> bool {
+        let len = self.list.len();
+        self.list.retain(|x| *x != w);
+        self.update();
+        self.list.len() != len
+    }
+    pub fn clear(&mut self) {
+        self.list.clear();
+        self.update();
+    }
+    fn update(&mut self) {
+        self.exec = false;
+        self.mem = false;
+        self.io = false;
+        for w in &self.list {
+            match w {
+                Watch::Exec(_) => self.exec = true,
+                Watch::Read { .. } | Watch::Write { .. } => self.mem = true,
+                Watch::In { .. } | Watch::Out { .. } => self.io = true,
+            }
+        }
+    }
+    pub fn has_exec(&self) -> bool {
+        self.exec
+    }
+    pub fn has_mem(&self) -> bool {
+        self.mem
+    }
+    pub fn has_io(&self) -> bool {
+        self.io
+    }
+    pub fn is_breakpoint(&self, pc: u16) -> bool {
+        self.list.contains(&Watch::Exec(pc))
+    }
+    //Only the first hit of an instruction is kept
+    fn set_hit(&mut self, reason: StopReason) {
+        if self.hit.is_none() {
+            self.hit = Some(reason);
+        }
+    }
+    pub fn take_hit(&mut self) -> Option<StopReason> {
+        self.hit.take()
+    }
+    pub fn on_read(&mut self, addr: u16, page: Option<u8>, value: u8) {
+        let hit = self.list.iter().any(|w| match *w {
+            Watch::Read { addr: a, page: p } => a == addr && (p.is_none() || p == page),
+            _ => false,
+        });
+        if hit {
+            self.set_hit(StopReason::Read(addr, value));
+        }
+    }
+    pub fn on_write(&mut self, addr: u16, page: Option<u8>, value: u8) {
+        let hit = self.list.iter().any(|w| match *w {
+            Watch::Write { addr: a, page: p } => a == addr && (p.is_none() || p == page),
+            _ => false,
+        });
+        if hit {
+            self.set_hit(StopReason::Write(addr, value));
+        }
+    }
+    pub fn on_in(&mut self, port: u16, value: u8) {
+        let hit = self.list.iter().any(|w| match *w {
+            Watch::In { port: p, mask } => (p ^ port) & mask == 0,
+            _ => false,
+        });
+        if hit {
+            self.set_hit(StopReason::In(port, value));
+        }
+    }
+    pub fn on_out(&mut self, port: u16, value: u8) {
+        let hit = self.list.iter().any(|w| match *w {
+            Watch::Out { port: p, mask } => (p ^ port) & mask == 0,
+            _ => false,
+        });
+        if hit {
+            self.set_hit(StopReason::Out(port, value));
+        }
+    }
+}
@@ -1,30 +1,82 @@
+use crate::debug::{StopReason, Watch, Watches};
 use crate::disk::Disk;
 use crate::floppy::Floppy;
 use crate::memory::Memory;
-use crate::psg::Psg;
+use crate::psg::{Psg, PsgChip, StereoMode};
 use crate::rzx;
-use crate::speaker::Speaker;
+use crate::speaker::{Speaker, SpeakerFilter};
 use crate::tape::{Tape, TapePos};
-use crate::z80::{self, Bus, Z80FileVersion, Z80};
+use crate::timing::Timing;
+use crate::ulaplus::{self, UlaPlus};
+use crate::z80::{self, disasm, Bus, Registers, Z80FileVersion, Z80};
 use anyhow::{anyhow, bail, Result};
 use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::io::{Cursor, Read, Write};
 
-const TIME_TO_INT: i32 = 69888;
+//Size of the border drawn around the 256x192 screen
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum BorderSize {
+    //No border, 256x192
+    None,
+    //Just a few pixels, 266x200
+    #[default]
+    Small,
+    //32 pixels at the sides, 24 at the top and bottom, 320x240
+    Normal,
+    //All the border visible in a TV, 352x296
+    Full,
+}
 
-//margins
-const BX0: usize = 5;
-const BX1: usize = 5;
-const BY0: usize = 4;
-const BY1: usize = 4;
+//Pixels of border at each side of the screen
+#[derive(Debug, Copy, Clone)]
+struct Margins {
+    left: usize,
+    right: usize,
+    top: usize,
+    bottom: usize,
+    //pixels of the image for every pixel of the ULA, horizontally
+    scale: usize,
+}
 
-//256x192 plus border
-const SCREEN_WIDTH: usize = BX0 + 256 + BX1;
-const SCREEN_HEIGHT: usize = BY0 + 192 + BY1;
-const SCREEN_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+impl BorderSize {
+    fn margins(self, model: Model) -> Margins {
+        let (left, right, top, bottom) = match self {
+            BorderSize::None => (0, 0, 0, 0),
+            BorderSize::Small => (5, 5, 4, 4),
+            BorderSize::Normal => (32, 32, 24, 24),
+            BorderSize::Full => (48, 48, 48, 56),
+        };
+        //the Timex hi-res mode has 512 pixels per row
+        let scale = if model == Model::Tc2048 { 2 } else { 1 };
+        //the Pentagon has fewer lines of bottom border
+        let bottom = bottom.min(Timing::for_model(model).bottom_lines() as usize);
+        Margins {
+            left,
+            right,
+            top,
+            bottom,
+            scale,
+        }
+    }
+}
 
-fn black_screen<PIX: Copy>(palette: &[[PIX; 8]; 2]) -> [PIX; SCREEN_SIZE] {
-    [palette[0][0]; SCREEN_SIZE]
+impl Margins {
+    //Size of the image, 256x192 plus border
+    fn width(&self) -> usize {
+        (self.left + 256 + self.right) * self.scale
+    }
+    //first pixel of the screen in every row of the image
+    fn screen_x(&self) -> usize {
+        self.left * self.scale
+    }
+    fn height(&self) -> usize {
+        self.top + 192 + self.bottom
+    }
+}
+
+fn black_screen<PIX: Copy>(palette: &[[PIX; 8]; 2], m: &Margins) -> Vec<PIX> {
+    vec![palette[0][0]; m.width() * m.height()]
 }
 
 struct RzxInfo {
@@ -34,20 +86,145 @@ struct RzxInfo {
     in_idx: usize,
 }
 
+//Border colors written by the CPU but not drawn yet
+struct BorderBeam {
+    //color at the position of the beam
+    color: u8,
+    //T-state of every change, from the start of the frame
+    changes: VecDeque<(i32, u8)>,
+}
+
+impl BorderBeam {
+    fn new(color: u8) -> BorderBeam {
+        BorderBeam {
+            color,
+            changes: VecDeque::new(),
+        }
+    }
+    fn push(&mut self, t: i32, color: u8) {
+        let last = self.changes.back().map_or(self.color, |&(_, c)| c);
+        if color != last {
+            self.changes.push_back((t, color));
+        }
+    }
+    //Color of the border at T-state `t`, it must not decrease between calls
+    fn color_at(&mut self, t: i32) -> u8 {
+        while let Some(&(tc, c)) = self.changes.front() {
+            if tc > t {
+                break;
+            }
+            self.color = c;
+            self.changes.pop_front();
+        }
+        self.color
+    }
+    //Discards the changes not drawn, at the end of the frame
+    fn reset(&mut self, color: u8) {
+        self.color = color;
+        self.changes.clear();
+    }
+}
+
+//Bitmap and attributes of the screen as read by the ULA while drawing the frame, so that
+//writes to video memory are seen only by the cells drawn after them
+struct ScreenFetch {
+    //one cell for every 8 pixels, in drawing order
+    cells: Vec<Cell>,
+    //number of cells already read in this frame
+    pos: usize,
+}
+
+//What the ULA reads for a group of 8 pixels
+#[derive(Debug, Copy, Clone, Default)]
+struct Cell {
+    bitmap: u8,
+    //in the Timex hi-res mode, this is the bitmap of the odd columns
+    attr: u8,
+    //Timex screen mode (port 0xff) when it was read, 0 in other models
+    mode: u8,
+}
+
+impl Cell {
+    fn fetch(screen: &[u8], mode: u8, y: usize, col: usize) -> Cell {
+        let (orow, arow) = screen_offsets(y);
+        //Timex modes: hi-res and hi-colour use both screens, with the same layout.
+        let (bitmap, attr) = if mode & 6 != 0 {
+            (screen[orow + col], screen[0x2000 + orow + col])
+        } else if mode & 1 != 0 {
+            (screen[0x2000 + orow + col], screen[0x2000 + arow + col])
+        } else {
+            (screen[orow + col], screen[arow + col])
+        };
+        Cell { bitmap, attr, mode }
+    }
+    fn is_hires(&self) -> bool {
+        self.mode & 4 != 0
+    }
+}
+
+impl ScreenFetch {
+    fn new() -> ScreenFetch {
+        ScreenFetch {
+            cells: vec![Cell::default(); 32 * 192],
+            pos: 0,
+        }
+    }
+    //Reads the cells that the ULA fetches before T-state `t`. Every cell is read 4 T-states
+    //after the previous one, at the start of the contended cycles.
+    fn fetch_until(&mut self, t: i32, timing: &Timing, memory: &Memory, mode: u8) {
+        let t = t - timing.contention_start;
+        if t <= 0 {
+            return;
+        }
+        let row = (t / timing.line) as usize;
+        let col = ((t % timing.line + 3) / 4).min(32) as usize;
+        let end = (32 * row + col).min(32 * 192);
+        self.fetch_to(end, memory, mode);
+    }
+    //Reads the cells before `end` not read yet
+    fn fetch_to(&mut self, end: usize, memory: &Memory, mode: u8) {
+        if end <= self.pos {
+            return;
+        }
+        let screen = memory.video_memory();
+        for i in self.pos..end {
+            self.cells[i] = Cell::fetch(screen, mode, i / 32, i % 32);
+        }
+        self.pos = end;
+    }
+}
+
 struct Ula {
+    model: Model,
+    timing: Timing,
     memory: Memory,
     keys: [u8; 9], //8 semirows plus joystick
+    //T-states of the current instruction, and how many of those are contention delays
+    cycle: u32,
     delay: u32,
     frame_counter: u32,
     time: i32,
     tape: Option<(Tape, Option<TapePos>)>,
     border: u8,
+    border_beam: BorderBeam,
+    screen: ScreenFetch,
     ear: bool,
     mic: bool,
     psg: Option<Psg>,
+    //the second PSG of the TurboSound, and if it is the selected one
+    turbo_sound: Option<Psg>,
+    turbo_sound_sel: bool,
+    //panning of the PSG channels
+    stereo: StereoMode,
     floppy: Option<Floppy>,
+    ulaplus: Option<UlaPlus>,
+    //Timex SCLD register, port 0xff. None in other models.
+    scld: Option<u8>,
+    //emulate the snow effect
+    snow: bool,
     fetch_count: u32,
     rzx_info: Option<RzxInfo>,
+    watches: Watches,
 }
 
 impl Ula {
@@ -56,6 +233,59 @@ impl Ula {
         self.delay = 0;
         r
     }
+    //Current T-state in the frame, in the middle of an instruction
+    fn now(&self) -> i32 {
+        self.time + self.cycle as i32
+    }
+    //Delays the CPU if the ULA is reading the screen right now
+    fn contend_now(&mut self) {
+        let line = self.timing.line;
+        let t = self.now() - self.timing.contention_start;
+        let ofs = t % line;
+        if !(0..line * 192).contains(&t) || ofs >= 128 {
+            return;
+        }
+        let d = self.timing.contention[(ofs % 8) as usize];
+        self.cycle += d;
+        self.delay += d;
+    }
+    //A bus cycle of `t` T-states with `addr` in the address bus
+    fn contend(&mut self, addr: u16, t: u32) {
+        if self.memory.is_contended(addr) {
+            self.contend_now();
+        }
+        self.cycle += t;
+    }
+    //An IO cycle: the delays depend both on the high byte of the port, as if it were a memory
+    //address, and on whether the ULA is selected (A0 = 0)
+    fn contend_io(&mut self, port: u16) {
+        //neither the Pentagon ULA nor the +2A/+3 gate array delay the IO cycles
+        if matches!(self.model, Model::Pentagon | Model::Plus2A | Model::Plus3) {
+            self.cycle += 4;
+            return;
+        }
+        let ula = port & 1 == 0;
+        match (self.memory.is_contended(port), ula) {
+            (false, false) => self.cycle += 4,
+            (false, true) => {
+                self.cycle += 1;
+                self.contend_now();
+                self.cycle += 3;
+            }
+            (true, true) => {
+                self.contend_now();
+                self.cycle += 1;
+                self.contend_now();
+                self.cycle += 3;
+            }
+            (true, false) => {
+                for _ in 0..4 {
+                    self.contend_now();
+                    self.cycle += 1;
+                }
+            }
+        }
+    }
     pub fn add_time(&mut self, t: u32, gui: &mut impl Gui) {
         self.time += t as i32;
         self.tape = match self.tape.take() {
@@ -78,11 +308,27 @@ impl Ula {
             tape => tape,
         };
     }
-    fn audio_sample(&mut self, t: u32) -> u32 {
+    //Left and right levels, the speaker is in the center
+    fn audio_sample(&mut self, t: u32) -> [u32; 2] {
         let v = if self.ear { 0x2000 } else { 0 } + if self.mic { 0x1000 } else { 0 };
-        match &mut self.psg {
-            None => v,
-            Some(psg) => v + u32::from(psg.next_sample(t)),
+        let mut res = [v, v];
+        let gains = self.stereo.gains();
+        for psg in self.psg.iter_mut().chain(&mut self.turbo_sound) {
+            let chans = psg.next_sample(t);
+            for (vol, gains) in chans.into_iter().zip(gains) {
+                for (r, g) in res.iter_mut().zip(gains) {
+                    *r += (f32::from(vol) * g) as u32;
+                }
+            }
+        }
+        res
+    }
+    //The PSG that receives the register reads and writes
+    fn selected_psg(&mut self) -> Option<&mut Psg> {
+        if self.turbo_sound_sel {
+            self.turbo_sound.as_mut()
+        } else {
+            self.psg.as_mut()
         }
     }
     fn has_to_interrupt(&self) -> bool {
@@ -90,23 +336,14 @@ impl Ula {
             let frame = &rzx.frames[rzx.frame_idx];
             self.fetch_count >= frame.fetch_count as u32
         } else {
-            self.time >= TIME_TO_INT
+            self.time >= self.timing.frame
         }
     }
     fn update_time_after_exec(&mut self, t: &mut u32, gui: &mut impl Gui) {
-        //contended memory and IO
-        let delay_m = self.memory.take_delay();
-        let delay_io = self.take_delay();
-        if self.time >= 224 * 64 && self.time < 224 * 256 && self.time % 224 < 128 {
-            //each row is 224 T, 128 are the real pixels where contention occurs
-            //we ignore the delay pattern (6,5,4,3,2,1,0,0) and instead do an
-            //estimation
-            match delay_m + delay_io {
-                0 => (),
-                1 => *t += 4,         //only 1 contention: these many Ts on average
-                x => *t += 6 * x - 2, //more than 1 contention: they use to chain so max up all but the first one
-            }
-        }
+        //contended memory and IO were delayed during the instruction
+        *t += self.take_delay();
+        debug_assert_eq!(*t, self.cycle);
+        self.cycle = 0;
         self.add_time(*t, gui);
     }
 
@@ -133,34 +370,174 @@ impl Ula {
             );
         } else {
             //we drag the excess T to the next loop
-            self.time -= TIME_TO_INT;
+            self.time -= self.timing.frame;
         }
     }
 
-    fn read_floating_bus(&self) -> u8 {
-        //reads stale data from the floating bus (last attr byte?)
-        let row = self.time / 224;
-        let ofs = self.time % 224;
-        if (64..256).contains(&row) && ofs < 128 {
-            let row = row - 64;
-            let ofs = ofs / 8 * 2 + 1; //attrs are read in pairs each 8 T, more or less
-            let addr = 32 * 192 + 32 * (row / 8) + ofs;
-            self.memory.video_memory()[addr as usize]
-        } else {
+    //The ULA reads the screen up to now, before it is modified
+    fn fetch_screen(&mut self) {
+        let mode = self.scld.unwrap_or(0);
+        self.screen
+            .fetch_until(self.now(), &self.timing, &self.memory, mode);
+    }
+    //Reading a port with no device attached returns the byte the ULA is reading from video
+    //memory, if any
+    fn read_floating_bus(&self, port: u16) -> u8 {
+        match self.model {
+            Model::Spec16k | Model::Spec48k | Model::Spec128k | Model::Plus2 | Model::Tc2048 => {
+                self.ula_fetch(false).unwrap_or(0xff)
+            }
+            //the Pentagon data bus has pull-up resistors
+            Model::Pentagon => 0xff,
+            //+2A/+3 only have it in ports 0x0ffd, 0x1ffd... and only while paging is not locked.
+            //They keep the last byte read during the idle cycles.
+            Model::Plus2A | Model::Plus3 => {
+                if port & 0xf003 == 0x0001 && !self.memory.is_locked() {
+                    self.ula_fetch(true).unwrap_or(0xff)
+                } else {
+                    0xff
+                }
+            }
+        }
+    }
+    //Byte being read by the ULA now. In every group of 8 T-states it reads bitmap, attribute,
+    //bitmap, attribute, then it is idle for 4 T-states. With `latched` the last attribute is
+    //returned while idle.
+    fn ula_fetch(&self, latched: bool) -> Option<u8> {
+        //the data bus is sampled one T-state before the end of the IO cycle
+        let (addr, _, _) = self.ula_fetch_pos(self.now() - 1, latched)?;
+        Some(self.memory.video_memory()[addr])
+    }
+    //What the ULA reads at T-state `t`: offset in video memory, cell index and if it is the
+    //attribute. The first bitmap byte is read 3 T-states after the contention starts.
+    fn ula_fetch_pos(&self, t: i32, latched: bool) -> Option<(usize, usize, bool)> {
+        let time = t - (self.timing.contention_start + 3);
+        let row = time / self.timing.line;
+        let ofs = time % self.timing.line;
+        if time < 0 || row >= 192 || ofs >= 128 {
             //borders or retraces
-            0xff
+            return None;
+        }
+        let step = match ofs % 8 {
+            s @ 0..=3 => s,
+            _ if latched => 3,
+            _ => return None,
+        };
+        let col = (ofs / 8 * 2 + step / 2) as usize;
+        let (orow, arow) = screen_offsets(row as usize);
+        let attr = step % 2 != 0;
+        let addr = if attr { arow } else { orow };
+        Some((addr + col, 32 * row as usize + col, attr))
+    }
+    //Reads memory at the end of the access, an unmapped address returns the floating bus
+    fn read_memory(&mut self, addr: u16) -> u8 {
+        if self.memory.is_mapped(addr) {
+            self.memory.peek(addr)
+        } else {
+            self.ula_fetch(false).unwrap_or(0xff)
+        }
+    }
+    //The refresh cycle puts IR in the address bus. If it is contended memory while the ULA is
+    //reading the screen, the ULA gets the byte with the low half of its address replaced by R,
+    //that is the snow effect.
+    fn snow(&mut self, ir: u16) {
+        if !self.snow || !self.memory.is_contended(ir) {
+            return;
+        }
+        //the refresh is in the last 2 T-states of the M1
+        for t in [self.now() - 2, self.now() - 1] {
+            let Some((addr, i, attr)) = self.ula_fetch_pos(t, false) else {
+                continue;
+            };
+            let value = self.memory.video_memory()[(addr & !0xff) | usize::from(ir as u8)];
+            let mode = self.scld.unwrap_or(0);
+            self.screen.fetch_to(i + 1, &self.memory, mode);
+            let cell = &mut self.screen.cells[i];
+            if attr {
+                cell.attr = value;
+            } else {
+                cell.bitmap = value;
+            }
         }
     }
 }
 
 impl Bus for Ula {
     fn peek(&mut self, addr: impl Into<u16>) -> u8 {
-        self.memory.peek(addr)
+        let addr = addr.into();
+        self.contend(addr, 3);
+        let value = self.read_memory(addr);
+        if self.watches.has_mem() {
+            let page = self.memory.ram_page(addr);
+            self.watches.on_read(addr, page, value);
+        }
+        value
+    }
+    fn peek_code(&mut self, addr: impl Into<u16>) -> u8 {
+        let addr = addr.into();
+        self.contend(addr, 3);
+        self.read_memory(addr)
+    }
+    fn fetch_opcode(&mut self, addr: impl Into<u16>) -> u8 {
+        let addr = addr.into();
+        self.contend(addr, 4);
+        self.read_memory(addr)
+    }
+    fn refresh(&mut self, ir: u16) {
+        self.snow(ir);
+    }
+    fn internal(&mut self, addr: impl Into<u16>, t: u32) {
+        //the +2A/+3 gate array only delays the memory accesses
+        if matches!(self.model, Model::Plus2A | Model::Plus3) {
+            self.cycle += t;
+            return;
+        }
+        let addr = addr.into();
+        for _ in 0..t {
+            self.contend(addr, 1);
+        }
+    }
+    fn idle(&mut self, t: u32) {
+        self.cycle += t;
     }
     fn poke(&mut self, addr: impl Into<u16>, value: u8) {
+        let addr = addr.into();
+        self.contend(addr, 3);
+        if self.memory.is_video(addr) {
+            self.fetch_screen();
+        }
         self.memory.poke(addr, value);
+        if self.watches.has_mem() {
+            let page = self.memory.ram_page(addr);
+            self.watches.on_write(addr, page, value);
+        }
     }
     fn do_in(&mut self, port: impl Into<u16>) -> u8 {
+        let port = port.into();
+        self.contend_io(port);
+        let value = self.read_port(port);
+        if self.watches.has_io() {
+            self.watches.on_in(port, value);
+        }
+        value
+    }
+    fn do_out(&mut self, port: impl Into<u16>, value: u8) {
+        let port = port.into();
+        self.contend_io(port);
+        self.write_port(port, value);
+        if self.watches.has_io() {
+            self.watches.on_out(port, value);
+        }
+    }
+    fn inc_fetch_count(&mut self, reason: z80::FetchReason) {
+        if reason != z80::FetchReason::Interrupt {
+            self.fetch_count += 1;
+        }
+    }
+}
+
+impl Ula {
+    fn read_port(&mut self, port: u16) -> u8 {
         if let Some(rzx) = &mut self.rzx_info {
             let frame = &rzx.frames[rzx.frame_data_idx];
             let b = match &frame.in_values {
@@ -188,16 +565,11 @@ impl Bus for Ula {
             };
             return b;
         }
-        let port = port.into();
         let lo = port as u8;
         let hi = (port >> 8) as u8;
         let mut r = 0xff;
         //ULA IO port
         if lo & 1 == 0 {
-            self.delay += 1;
-            if (0x4000..0x8000).contains(&port) {
-                self.delay += 1;
-            }
             for i in 0..8 {
                 //half row keyboard
                 if hi & (1 << i) == 0 {
@@ -210,15 +582,12 @@ impl Bus for Ula {
                 }
             }
         } else {
-            if (0x4000..0x8000).contains(&port) {
-                self.delay += 4;
-            }
             match lo {
                 0xfd => {
                     match hi {
                         // Programmable Sound Generator
                         0xff => {
-                            if let Some(psg) = &self.psg {
+                            if let Some(psg) = self.selected_psg() {
                                 r = psg.read_reg();
                             }
                         }
@@ -235,22 +604,32 @@ impl Bus for Ula {
                             }
                         }
                         // Some +3 games use 0x0ffd as floating bus
-                        0x0f => r = self.read_floating_bus(),
+                        0x0f => r = self.read_floating_bus(port),
                         _ => {
-                            r = self.read_floating_bus();
+                            r = self.read_floating_bus(port);
                             log::info!("IN {:04x}, {:02x}", port, r);
                         }
                     }
                 }
-                // If all bits are 1, no device is selected, it reads the floating bus
-                0xff => r = self.read_floating_bus(),
+                // The Timex SCLD, otherwise no device is selected, it reads the floating bus
+                0xff => {
+                    r = match self.scld {
+                        Some(scld) => scld,
+                        None => self.read_floating_bus(port),
+                    }
+                }
+                0x3b if hi == 0xff && self.ulaplus.is_some() => {
+                    if let Some(ulaplus) = &self.ulaplus {
+                        r = ulaplus.read_data();
+                    }
+                }
 
                 x if x & 0x20 == 0 => {
                     //kempston joystick (0x1f | 0xdf ...)
                     r = self.keys[8];
                 }
                 _ => {
-                    r = self.read_floating_bus();
+                    r = self.read_floating_bus(port);
                     log::info!("IN {:04x}, {:02x}", port, r);
                 }
             }
@@ -258,25 +637,17 @@ impl Bus for Ula {
         r
     }
 
-    fn do_out(&mut self, port: impl Into<u16>, value: u8) {
-        let port = port.into();
+    fn write_port(&mut self, port: u16, value: u8) {
         let lo = port as u8;
         let hi = (port >> 8) as u8;
         if lo & 1 == 0 {
             //ULA IO port
-            self.delay += 1;
-            if (0x4000..0x8000).contains(&port) {
-                self.delay += 1;
-            }
             self.border = value & 7;
+            self.border_beam.push(self.now(), self.border);
             self.ear = (value & 0x10) != 0;
             self.mic = (value & 0x08) != 0;
         } else {
             //log::info!("OUT {:04x}, {:02x}", port, value);
-            if (0x4000..0x8000).contains(&port) {
-                self.delay += 4;
-            }
-            #[allow(clippy::single_match)]
             match lo {
                 0xfd => {
                     //128 stuff
@@ -284,20 +655,28 @@ impl Bus for Ula {
                         0x7f => {
                             //Memory banks
                             //log::info!("MEM {:04x}, {:02x}", port, value);
+                            self.fetch_screen();
                             self.memory.switch_banks(value);
                         }
-                        0x1f => {
-                            //+2 Memory banks
+                        0x1f if !matches!(self.model, Model::Plus2 | Model::Pentagon) => {
+                            //+2A Memory banks
                             //log::info!("MEM+2 {:04x}, {:02x}", port, value);
+                            self.fetch_screen();
                             self.memory.switch_banks_plus2(value);
                         }
-                        0xff => {
-                            if let Some(psg) = &mut self.psg {
-                                psg.select_reg(value);
+                        0xff => match value {
+                            //TurboSound: 0xff selects the first chip, 0xfe the second one
+                            0xfe | 0xff if self.turbo_sound.is_some() => {
+                                self.turbo_sound_sel = value == 0xfe;
                             }
-                        }
+                            _ => {
+                                if let Some(psg) = self.selected_psg() {
+                                    psg.select_reg(value);
+                                }
+                            }
+                        },
                         0xbf => {
-                            if let Some(psg) = &mut self.psg {
+                            if let Some(psg) = self.selected_psg() {
                                 psg.write_reg(value);
                             }
                         }
@@ -328,35 +707,67 @@ impl Bus for Ula {
                         }
                     }
                 }
+                0xff if self.scld.is_some() => {
+                    //Timex screen mode
+                    self.fetch_screen();
+                    self.scld = Some(value);
+                }
+                0x3b => {
+                    if let Some(ulaplus) = &mut self.ulaplus {
+                        match hi {
+                            0xbf => ulaplus.select_reg(value),
+                            0xff => ulaplus.write_data(value),
+                            _ => {}
+                        }
+                    }
+                }
                 _ => {
                     log::info!("OUT {:04x}, {:02x}", port, value);
                 }
             }
         }
     }
-    fn inc_fetch_count(&mut self, reason: z80::FetchReason) {
-        if reason != z80::FetchReason::Interrupt {
-            self.fetch_count += 1;
-        }
-    }
 }
 
 pub trait Gui {
     type Pixel: Copy + 'static;
     //Palette of colors: 2 intensities, each with 8 basic colors
     const PALETTE: [[Self::Pixel; 8]; 2];
+    //Builds a pixel from its RGB value, for the colors of the ULAplus palette
+    fn rgb(r: u8, g: u8, b: u8) -> Self::Pixel;
 
     fn on_rzx_running(&mut self, running: bool, percent: u32);
     fn on_tape_block(&mut self, index: usize);
+    //Stereo audio, the left and right samples are interleaved
     fn put_sound_data(&mut self, data: &[f32]);
     fn put_image_data(&mut self, w: usize, h: usize, data: &[Self::Pixel]);
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Model {
+    //A 48K with only the first 16K of RAM
+    Spec16k,
     Spec48k,
     Spec128k,
+    //The grey +2, a 128K in another case
+    Plus2,
+    //The black +2A/+2B, a +3 without the floppy drive
+    Plus2A,
     Plus3,
+    //Pentagon 128, the Russian clone
+    Pentagon,
+    //Timex TC2048, a 48K with extra screen modes
+    Tc2048,
+}
+
+impl Model {
+    //Has the memory banks of the 128K
+    pub(crate) fn is_128k(self) -> bool {
+        matches!(
+            self,
+            Model::Spec128k | Model::Plus2 | Model::Plus2A | Model::Plus3 | Model::Pentagon
+        )
+    }
 }
 
 pub struct Game<GUI: Gui> {
@@ -364,22 +775,25 @@ pub struct Game<GUI: Gui> {
     z80: Z80,
     ula: Ula,
     speaker: Speaker,
-    image: [GUI::Pixel; SCREEN_SIZE],
+    border_size: BorderSize,
+    margins: Margins,
+    image: Vec<GUI::Pixel>,
+    //colors of the ULAplus palette
+    clut: [GUI::Pixel; 64],
+    //next row of the image to be drawn, including the border
+    screen_row: usize,
+    //PC of the last breakpoint hit, so that it is not hit again when resuming
+    break_at: Option<u16>,
 }
 
-fn write_border_row<PIX: Copy>(y: usize, border: PIX, ps: &mut [PIX]) {
-    let prow = &mut ps[SCREEN_WIDTH * y..SCREEN_WIDTH * (y + 1)];
+fn write_border_row<PIX: Copy>(y: usize, border: PIX, m: &Margins, ps: &mut [PIX]) {
+    let w = m.width();
+    let prow = &mut ps[w * y..w * (y + 1)];
     prow.fill(border);
 }
 
-fn write_screen_row<PIX: Copy>(
-    y: usize,
-    border: PIX,
-    inv: bool,
-    data: &[u8],
-    palette: &[[PIX; 8]; 2],
-    ps: &mut [PIX],
-) {
+//Offsets in video memory of the bitmap and the attributes of the pixel row `y`
+fn screen_offsets(y: usize) -> (usize, usize) {
     let orow = match y {
         0..=63 => (y % 8) * 256 + (y / 8) * 32,
         64..=127 => {
@@ -394,87 +808,171 @@ fn write_screen_row<PIX: Copy>(
         }
         _ => unreachable!(),
     };
-    let ym = y + BY0;
-    let prow_full = &mut ps[SCREEN_WIDTH * ym..SCREEN_WIDTH * (ym + 1)];
-    prow_full[..BX0].fill(border);
-    prow_full[BX0 + 256..].fill(border);
-    let prow = &mut prow_full[BX0..BX0 + 256];
     let arow = 192 * 32 + (y / 8) * 32;
+    (orow, arow)
+}
 
-    //Attributes are 8 bits:
-    // b7: blink
-    // b6: bright
-    // b5-b3: bk color
-    // b2-b0: fg color
-    //Bitmap and attribute addresses are related in a funny way.
-    //Binary values are grouped as octal, clippy doesn't seem to like that.
-    #[allow(clippy::unusual_byte_groupings)]
-    for ((&bits, &attr), pixels) in data[orow..orow + 32]
-        .iter()
-        .zip(&data[arow..arow + 32])
-        .zip(prow.chunks_mut(8))
-    {
-        let bright = (attr & 0b01_000_000) != 0;
-        let colors = &palette[bright as usize];
-        let ink = colors[(attr & 0b00_000_111) as usize];
-        let paper = colors[((attr & 0b00_111_000) >> 3) as usize];
-        let inv = inv && (attr & 0b10_000_000) != 0;
-        let mut bits = if inv { bits ^ 0xff } else { bits };
-        pixels.fill_with(|| {
-            let on = bits & 0x80 != 0;
-            bits <<= 1;
-            if on {
-                ink
-            } else {
-                paper
+//How the attributes and the border are converted to pixels
+enum Colors<'a, PIX> {
+    //The 2x8 colors of the Gui. If the bool is set, flashing attributes are inverted.
+    Classic([[PIX; 8]; 2], bool),
+    //ULAplus palette mode
+    Clut(&'a [PIX; 64]),
+}
+
+impl<'a, PIX: Copy> Colors<'a, PIX> {
+    fn new(
+        palette: [[PIX; 8]; 2],
+        ulaplus: &Option<UlaPlus>,
+        clut: &'a [PIX; 64],
+        inverted: bool,
+    ) -> Colors<'a, PIX> {
+        match ulaplus {
+            Some(ulaplus) if ulaplus.palette_mode() => Colors::Clut(clut),
+            _ => Colors::Classic(palette, inverted),
+        }
+    }
+    //Ink and paper of an attribute
+    fn ink_paper(&self, attr: u8) -> (PIX, PIX) {
+        match self {
+            Colors::Classic(palette, inv) => {
+                //Attributes are 8 bits:
+                // b7: blink
+                // b6: bright
+                // b5-b3: bk color
+                // b2-b0: fg color
+                //Binary values are grouped as octal, clippy doesn't seem to like that.
+                #[allow(clippy::unusual_byte_groupings)]
+                let (bright, blink) = (attr & 0b01_000_000 != 0, attr & 0b10_000_000 != 0);
+                let colors = &palette[bright as usize];
+                let ink = colors[usize::from(attr & 7)];
+                let paper = colors[usize::from((attr >> 3) & 7)];
+                if *inv && blink {
+                    (paper, ink)
+                } else {
+                    (ink, paper)
+                }
             }
-        });
+            Colors::Clut(clut) => {
+                let (ink, paper) = ulaplus::clut_index(attr);
+                (clut[ink], clut[paper])
+            }
+        }
+    }
+    //Ink and paper of the Timex hi-res mode, they are complementary and never bright
+    fn hires_ink_paper(&self, mode: u8) -> (PIX, PIX) {
+        let ink = (mode >> 3) & 7;
+        (self.border(ink), self.border(7 - ink))
+    }
+    fn border(&self, border: u8) -> PIX {
+        match self {
+            //Border is never bright
+            Colors::Classic(palette, _) => palette[0][usize::from(border)],
+            Colors::Clut(clut) => clut[ulaplus::border_index(border)],
+        }
+    }
+}
+
+//Draws the pixel row `y` of the screen from its 32 cells
+fn write_screen_row<PIX: Copy>(
+    y: usize,
+    cells: &[Cell],
+    colors: &Colors<PIX>,
+    m: &Margins,
+    ps: &mut [PIX],
+) {
+    let start = m.width() * (y + m.top) + m.screen_x();
+    let prow = &mut ps[start..start + 256 * m.scale];
+
+    for (cell, pixels) in cells.iter().zip(prow.chunks_mut(8 * m.scale)) {
+        //16 half pixels, so that both modes are drawn the same
+        let (bits, (ink, paper)) = if cell.is_hires() {
+            let bits = u16::from_be_bytes([cell.bitmap, cell.attr]);
+            (bits, colors.hires_ink_paper(cell.mode))
+        } else {
+            let bits = (0..8).fold(0, |bits, i| {
+                let b = u16::from((cell.bitmap >> i) & 1);
+                bits | (b * 3) << (2 * i)
+            });
+            (bits, colors.ink_paper(cell.attr))
+        };
+        for (x, pixel) in pixels.iter_mut().enumerate() {
+            let on = bits & (0x8000 >> (x * 2 / m.scale)) != 0;
+            *pixel = if on { ink } else { paper };
+        }
+    }
+}
+
+//Draws the pixels `xs` of the row `y` of the image with the border color of the moment each
+//one is drawn. The ULA reads the border color every 4 T-states, that is 8 pixels. `t0` is the
+//T-state of the first pixel of the screen in this row, the left border is drawn before that.
+fn write_border_pixels<PIX: Copy>(
+    y: usize,
+    xs: std::ops::Range<usize>,
+    t0: i32,
+    beam: &mut BorderBeam,
+    colors: &Colors<PIX>,
+    m: &Margins,
+    ps: &mut [PIX],
+) {
+    let w = m.width();
+    let prow = &mut ps[w * y..w * (y + 1)];
+    for x in xs {
+        let t = t0 + ((x / m.scale) as i32 - m.left as i32).div_euclid(8) * 4;
+        prow[x] = colors.border(beam.color_at(t));
     }
 }
 
 fn write_screen<PIX: Copy>(
-    border: PIX,
-    palette: &[[PIX; 8]; 2],
-    inv: bool,
+    border: u8,
+    colors: &Colors<PIX>,
     data: &[u8],
+    mode: u8,
+    m: &Margins,
     ps: &mut [PIX],
 ) {
-    for y in 0..BY0 {
-        write_border_row(y, border, ps);
+    let border = colors.border(border);
+    for y in 0..m.top {
+        write_border_row(y, border, m, ps);
     }
+    let w = m.width();
     for y in 0..192 {
-        write_screen_row(y, border, inv, data, palette, ps);
+        let cells: Vec<Cell> = (0..32).map(|col| Cell::fetch(data, mode, y, col)).collect();
+        write_screen_row(y, &cells, colors, m, ps);
+        let prow = &mut ps[w * (m.top + y)..w * (m.top + y + 1)];
+        prow[..m.screen_x()].fill(border);
+        prow[m.screen_x() + 256 * m.scale..].fill(border);
     }
-    for y in 0..BY1 {
-        write_border_row(BY0 + 192 + y, border, ps);
+    for y in 0..m.bottom {
+        write_border_row(m.top + 192 + y, border, m, ps);
     }
 }
 
 // General speed of the emulation is controlled by the audio output.
-// The main audio channel should be configured at 22050 Hz, this function will return how many
-// CPU ticks are required for every audio sample.
-fn t_per_sample(model: Model) -> u32 {
-    const SAMPLER: u32 = 22050;
-    // CPU freq is slightly different for different models
-    let cpu_freq = match model {
-        Model::Spec48k => 3_500_000,
-        Model::Spec128k | Model::Plus3 => 3_546_900,
-    };
-    // Round to nearest, that will give a maximum relative error in the emulation speed of:
-    // (22.05k / 3.5M / 2) = 0.3%
-    // That I think is acceptable. To get exact timings we would need to choose a sample output rate that is an exact division of the
-    // CPU freq. But that would require resampling somewhere in the audio pipeline, and that could decrease performance.
-    (cpu_freq + SAMPLER / 2) / SAMPLER
-}
+// This is the sample rate of the audio generated by default, change it with
+// `Game::set_sample_rate()` to match the audio device.
+const DEFAULT_SAMPLE_RATE: u32 = 22050;
+
+// Length of the SNA snapshots: the 27 bytes header and 48K of RAM. The 128K ones add the PC,
+// the paging and the other 5 banks, or 6 if the paged bank is repeated.
+const SNA_48K_LEN: usize = 27 + 0xc000;
+const SNA_128K_LEN: usize = SNA_48K_LEN + 4 + 5 * 0x4000;
+const SNA_128K_LEN_BIG: usize = SNA_128K_LEN + 0x4000;
 
 impl<GUI: Gui> Game<GUI> {
     pub fn new(model: Model, gui: &mut GUI) -> Game<GUI> {
         log::info!("Go!");
         let memory = Memory::new_from_model(model);
+        let timing = Timing::for_model(model);
         let (psg, floppy) = match model {
-            Model::Spec48k => (None, None),
-            Model::Spec128k => (Some(Psg::new()), None),
-            Model::Plus3 => (Some(Psg::new()), Some(Floppy::new())),
+            Model::Spec16k | Model::Spec48k | Model::Tc2048 => (None, None),
+            Model::Spec128k | Model::Plus2 | Model::Plus2A | Model::Pentagon => {
+                (Some(Psg::new(timing.psg_clock, timing.cpu_freq)), None)
+            }
+            Model::Plus3 => (
+                Some(Psg::new(timing.psg_clock, timing.cpu_freq)),
+                Some(Floppy::new()),
+            ),
         };
         gui.on_rzx_running(false, 0);
         Game::from_parts(model, Z80::new(), memory, 0, psg, floppy)
@@ -492,96 +990,334 @@ impl<GUI: Gui> Game<GUI> {
             model,
             z80,
             ula: Ula {
+                model,
+                timing: Timing::for_model(model),
                 memory,
                 keys: Default::default(),
+                cycle: 0,
                 delay: 0,
                 frame_counter: 0,
                 time: 0,
                 tape: None,
                 border,
+                border_beam: BorderBeam::new(border),
+                screen: ScreenFetch::new(),
                 ear: false,
                 mic: false,
                 psg,
+                turbo_sound: None,
+                turbo_sound_sel: false,
+                stereo: StereoMode::default(),
                 floppy,
+                ulaplus: None,
+                scld: (model == Model::Tc2048).then_some(0),
+                snow: true,
                 fetch_count: 0,
                 rzx_info: None,
+                watches: Watches::default(),
             },
-            speaker: Speaker::new(t_per_sample(model)),
-            image: black_screen(&GUI::PALETTE),
+            speaker: Speaker::new(Timing::for_model(model).cpu_freq, DEFAULT_SAMPLE_RATE),
+            border_size: BorderSize::default(),
+            margins: BorderSize::default().margins(model),
+            image: black_screen(&GUI::PALETTE, &BorderSize::default().margins(model)),
+            clut: [GUI::PALETTE[0][0]; 64],
+            screen_row: 0,
+            break_at: None,
         }
     }
 
     pub fn model(&self) -> Model {
         self.model
     }
-    pub fn psg_status(&self) -> Option<[u8; 17]> {
-        self.ula.psg.as_ref().map(|psg| {
+    pub fn sample_rate(&self) -> u32 {
+        self.speaker.sample_rate()
+    }
+    //Samples per second of the data sent to `Gui::put_sound_data()`
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        if rate != self.sample_rate() {
+            let filter = self.speaker.filter();
+            self.speaker = Speaker::new(self.ula.timing.cpu_freq, rate);
+            self.speaker.set_filter(filter);
+        }
+    }
+    //The emulated chip of the PSG, None if there is no PSG
+    pub fn psg_chip(&self) -> Option<PsgChip> {
+        self.ula.psg.as_ref().map(|psg| psg.chip())
+    }
+    pub fn set_psg_chip(&mut self, chip: PsgChip) {
+        let ula = &mut self.ula;
+        for psg in ula.psg.iter_mut().chain(&mut ula.turbo_sound) {
+            psg.set_chip(chip);
+        }
+    }
+    pub fn turbo_sound(&self) -> bool {
+        self.ula.turbo_sound.is_some()
+    }
+    //Adds a second PSG, only if the model already has one
+    pub fn set_turbo_sound(&mut self, enabled: bool) {
+        let ula = &mut self.ula;
+        if enabled == ula.turbo_sound.is_some() {
+            return;
+        }
+        ula.turbo_sound_sel = false;
+        ula.turbo_sound = match &ula.psg {
+            Some(psg) if enabled => {
+                let mut psg2 = Psg::new(ula.timing.psg_clock, ula.timing.cpu_freq);
+                psg2.set_chip(psg.chip());
+                Some(psg2)
+            }
+            _ => None,
+        };
+    }
+    pub fn stereo_mode(&self) -> StereoMode {
+        self.ula.stereo
+    }
+    pub fn set_stereo_mode(&mut self, mode: StereoMode) {
+        self.ula.stereo = mode;
+    }
+    pub fn speaker_filter(&self) -> SpeakerFilter {
+        self.speaker.filter()
+    }
+    pub fn set_speaker_filter(&mut self, filter: SpeakerFilter) {
+        if filter != self.speaker_filter() {
+            self.speaker.set_filter(filter);
+        }
+    }
+    pub fn ulaplus(&self) -> bool {
+        self.ula.ulaplus.is_some()
+    }
+    //Enables the ULAplus ports, disabling it goes back to the classic colors
+    pub fn set_ulaplus(&mut self, enabled: bool) {
+        if enabled != self.ulaplus() {
+            self.ula.ulaplus = enabled.then(UlaPlus::new);
+        }
+    }
+    pub fn snow(&self) -> bool {
+        self.ula.snow
+    }
+    pub fn set_snow(&mut self, enabled: bool) {
+        self.ula.snow = enabled;
+    }
+    pub fn border_size(&self) -> BorderSize {
+        self.border_size
+    }
+    //Changes the size of the image sent to `put_image_data`, from the next frame
+    pub fn set_border_size(&mut self, size: BorderSize) {
+        if size == self.border_size {
+            return;
+        }
+        self.border_size = size;
+        self.margins = size.margins(self.model);
+        self.image = black_screen(&GUI::PALETTE, &self.margins);
+    }
+    //The selected register and the values of the registers of a PSG. `index` is 0, or 1 for the
+    //second chip of the TurboSound.
+    pub fn psg_status(&self, index: usize) -> Option<[u8; 17]> {
+        let psg = match index {
+            0 => &self.ula.psg,
+            1 => &self.ula.turbo_sound,
+            _ => &None,
+        };
+        psg.as_ref().map(|psg| {
             let mut res = [0; 17];
             psg.snapshot(&mut res);
             res
         })
     }
-    pub fn draw_frame(&mut self, turbo: bool, gui: &mut GUI) {
+    // Runs a single instruction, returns the T-states used.
+    // If `render` the audio and the screen rows already finished are generated.
+    fn exec_instruction(&mut self, render: bool, gui: &mut GUI) -> u32 {
+        #[cfg(feature = "dump_ops")]
+        {
+            let pc = self.z80.registers().pc;
+            log::debug!("{pc:04x} {}", self.disassemble(pc).0);
+            self.z80._dump_regs();
+        }
+        let mut t = self.z80.exec(&mut self.ula);
+        self.ula.update_time_after_exec(&mut t, gui);
+        //the ULA holds the INT line only for a few T-states after the start of the frame
+        if self.ula.time >= self.ula.timing.int_length {
+            self.z80.set_int(false);
+        }
+
+        if render {
+            let sample = self.ula.audio_sample(t);
+            self.speaker.push_sample(sample, t);
+            self.draw_rows(self.ula.time);
+        }
+        t
+    }
+    //Draws the rows of the image, including the border, that the beam completes before `time`
+    fn draw_rows(&mut self, time: i32) {
+        self.update_clut();
+        let inverted = self.ula.frame_counter % 32 < 16;
+        let colors = Colors::new(GUI::PALETTE, &self.ula.ulaplus, &self.clut, inverted);
+        let timing = self.ula.timing;
+        //scanline of the first row of the image, including the border
+        let m = self.margins;
+        let first_row = timing.first_line as usize - m.top;
+        let ula = &mut self.ula;
+        let mode = ula.scld.unwrap_or(0);
+        ula.screen.fetch_until(time, &timing, &ula.memory, mode);
+        while self.screen_row < first_row + m.height()
+            && (self.screen_row as i32 + 1) * timing.line + timing.line_offset <= time
+        {
+            let y = self.screen_row.wrapping_sub(first_row);
+            let t0 = self.screen_row as i32 * timing.line + timing.line_offset;
+            let beam = &mut self.ula.border_beam;
+            let image = &mut self.image;
+            let colors = &colors;
+            if (m.top..m.top + 192).contains(&y) {
+                let cells = 32 * (y - m.top)..32 * (y - m.top + 1);
+                let cells = &self.ula.screen.cells[cells];
+                write_screen_row(y - m.top, cells, colors, &m, image);
+                write_border_pixels(y, 0..m.screen_x(), t0, beam, colors, &m, image);
+                let xs = m.screen_x() + 256 * m.scale..m.width();
+                write_border_pixels(y, xs, t0, beam, colors, &m, image);
+            } else if y < m.height() {
+                let xs = 0..m.width();
+                write_border_pixels(y, xs, t0, beam, colors, &m, image);
+            }
+            self.screen_row += 1;
+        }
+    }
+    //Converts the ULAplus palette to pixels, if it changed
+    fn update_clut(&mut self) {
+        if let Some(ulaplus) = &mut self.ula.ulaplus {
+            if ulaplus.take_changed() {
+                for (i, pixel) in self.clut.iter_mut().enumerate() {
+                    let (r, g, b) = ulaplus.rgb(i);
+                    *pixel = GUI::rgb(r, g, b);
+                }
+            }
+        }
+    }
+    fn end_frame(&mut self, render: bool, gui: &mut GUI) {
+        //with a big border the last rows of the image may end after the interrupt
+        if render {
+            self.draw_rows(i32::MAX);
+        }
+        self.ula.post_interrupt(gui);
+        //the Timex can disable the interrupt
+        let int_enabled = self.ula.scld.is_none_or(|scld| scld & 0x40 == 0);
+        self.z80.set_int(int_enabled);
+        self.ula.frame_counter = self.ula.frame_counter.wrapping_add(1);
+        self.ula.border_beam.reset(self.ula.border);
+        self.ula.screen.pos = 0;
+        self.screen_row = 0;
+    }
+    // Runs the instruction at PC, unless there is a breakpoint there, and checks the watchpoints
+    fn exec_watched(&mut self, render: bool, gui: &mut GUI) -> Option<StopReason> {
+        if self.ula.watches.has_exec() {
+            let regs = self.z80.registers();
+            let pc = regs.pc;
+            if self.break_at.take() != Some(pc)
+                && !regs.halted
+                && self.ula.watches.is_breakpoint(pc)
+            {
+                self.break_at = Some(pc);
+                return Some(StopReason::Breakpoint(pc));
+            }
+        }
+        self.exec_instruction(render, gui);
+        self.ula.watches.take_hit()
+    }
+    //Runs until the end of the frame, or until a breakpoint or watchpoint is hit. In that case
+    //the image is partially drawn and the next call continues from the same point.
+    pub fn draw_frame(&mut self, turbo: bool, gui: &mut GUI) -> Option<StopReason> {
         //log::info!("Draw!");
         let n = if turbo { 100 } else { 1 };
 
-        for _ in 0..n {
-            self.ula.frame_counter = self.ula.frame_counter.wrapping_add(1);
-            let inverted = self.ula.frame_counter % 32 < 16;
-            let mut screen_time = 0;
-            let mut screen_row = 0;
+        let mut stop = None;
+        'frames: for _ in 0..n {
             while !self.ula.has_to_interrupt() {
-                let mut t = self.z80.exec(&mut self.ula);
-                //self.z80._dump_regs();
-                self.ula.update_time_after_exec(&mut t, gui);
-
-                if !turbo {
-                    let sample = self.ula.audio_sample(t);
-                    self.speaker.push_sample(sample, t);
-                    //Border is never bright
-                    let border = GUI::PALETTE[0][self.ula.border as usize];
-                    screen_time += t as i32;
-                    while screen_time >= 224 {
-                        screen_time -= 224;
-                        match screen_row {
-                            60..=63 | 256..=259 => {
-                                write_border_row(screen_row - 60, border, &mut self.image);
-                            }
-                            64..=255 => {
-                                let screen = self.ula.memory.video_memory();
-                                write_screen_row(
-                                    screen_row - 64,
-                                    border,
-                                    inverted,
-                                    screen,
-                                    &GUI::PALETTE,
-                                    &mut self.image,
-                                );
-                            }
-                            _ => {}
-                        }
-                        screen_row += 1;
-                    }
+                stop = self.exec_watched(!turbo, gui);
+                if stop.is_some() {
+                    break 'frames;
                 }
             }
-            self.z80.interrupt();
-            self.ula.post_interrupt(gui);
+            self.end_frame(!turbo, gui);
         }
         if turbo {
+            self.update_clut();
+            let colors = Colors::new(GUI::PALETTE, &self.ula.ulaplus, &self.clut, false);
             let screen = self.ula.memory.video_memory();
-            //Border is never bright
-            let border = GUI::PALETTE[0][self.ula.border as usize];
-            write_screen(border, &GUI::PALETTE, false, screen, &mut self.image);
-        } else {
+            let mode = self.ula.scld.unwrap_or(0);
+            write_screen(
+                self.ula.border,
+                &colors,
+                screen,
+                mode,
+                &self.margins,
+                &mut self.image,
+            );
+        } else if stop.is_none() {
             //adding samples should be rarely necessary, so use lazy generation
             let ula = &mut self.ula;
             let audio = self
                 .speaker
-                .complete_frame(TIME_TO_INT as u32, || ula.audio_sample(0));
+                .complete_frame(ula.timing.frame as u32, || ula.audio_sample(0));
             gui.put_sound_data(audio);
             self.speaker.clear();
         }
-        gui.put_image_data(SCREEN_WIDTH, SCREEN_HEIGHT, &self.image);
+        self.put_image(gui);
+        stop
+    }
+    fn put_image(&self, gui: &mut GUI) {
+        let m = &self.margins;
+        if m.scale == 1 {
+            gui.put_image_data(m.width(), m.height(), &self.image);
+            return;
+        }
+        //repeat the rows to keep the aspect ratio
+        let image: Vec<GUI::Pixel> = self
+            .image
+            .chunks(m.width())
+            .flat_map(|row| std::iter::repeat_n(row, m.scale).flatten().copied())
+            .collect();
+        gui.put_image_data(m.width(), m.height() * m.scale, &image);
+    }
+    //Runs a single instruction, or the interrupt routine if one is pending. If the frame ends the
+    //interrupt is signaled, but no image or sound is generated. Returns the T-states used.
+    //Breakpoints and watchpoints are ignored.
+    //Stepping is silent: neither the speaker nor the AY advance, the sound restarts with the next
+    //frame run normally.
+    pub fn step_instruction(&mut self, gui: &mut GUI) -> u32 {
+        self.break_at = None;
+        let t = self.exec_instruction(false, gui);
+        self.ula.watches.take_hit();
+        if self.ula.has_to_interrupt() {
+            self.end_frame(false, gui);
+        }
+        t
+    }
+    //Runs instructions for at least `t` T-states, returns the T-states actually used.
+    //Like step_instruction(), it produces no sound.
+    pub fn run_tstates(&mut self, t: u32, gui: &mut GUI) -> u32 {
+        let mut done = 0;
+        while done < t {
+            done += self.step_instruction(gui);
+        }
+        done
+    }
+    pub fn registers(&self) -> Registers {
+        self.z80.registers()
+    }
+    pub fn watches(&self) -> &[Watch] {
+        self.ula.watches.list()
+    }
+    pub fn add_watch(&mut self, watch: Watch) {
+        self.ula.watches.add(watch);
+    }
+    //Returns false if the watch did not exist
+    pub fn remove_watch(&mut self, watch: Watch) -> bool {
+        self.ula.watches.remove(watch)
+    }
+    pub fn clear_watches(&mut self) {
+        self.ula.watches.clear();
+    }
+    pub fn set_registers(&mut self, regs: &Registers) {
+        self.z80.set_registers(regs);
     }
     //Every byte in key is a key pressed:
     //  * low nibble: key number (0..5)
@@ -604,8 +1340,16 @@ impl<GUI: Gui> Game<GUI> {
             keys >>= 8;
         }
     }
+    //Triggers a non-maskable interrupt, as the "magic button" of some peripherals
+    pub fn nmi(&mut self) {
+        self.z80.nmi();
+    }
     pub fn peek(&mut self, addr: u16) -> u8 {
-        self.ula.memory.peek_no_delay(addr)
+        self.ula.memory.peek(addr)
+    }
+    //Disassembles the instruction at `addr`, returns the text and its length in bytes
+    pub fn disassemble(&self, addr: u16) -> (String, u16) {
+        disasm::disasm(|a| self.ula.memory.peek(a), addr)
     }
     pub fn poke(&mut self, addr: u16, value: u8) {
         self.ula.memory.poke(addr, value);
@@ -682,7 +1426,10 @@ impl<GUI: Gui> Game<GUI> {
         //With the 128K, we will save V2 or V3 depending on the plus2 memory bank
         let banks_plus2 = self.ula.memory.last_banks_plus2();
 
-        let v3 = self.model == Model::Plus3 || banks_plus2 != 0;
+        let v3 = matches!(
+            self.model,
+            Model::Plus2 | Model::Plus2A | Model::Plus3 | Model::Pentagon | Model::Tc2048
+        ) || banks_plus2 != 0;
 
         const HEADER: usize = 32;
         let header_extra = if v3 { 55 } else { 23 };
@@ -701,18 +1448,29 @@ impl<GUI: Gui> Game<GUI> {
         data[7] = 0;
         //hw mode
         data[34] = match self.model {
-            Model::Spec48k => 0,
+            Model::Spec16k | Model::Spec48k => 0,
+            Model::Spec128k if v3 => 4,
             Model::Spec128k => 3,
+            Model::Plus2 => 12,
+            Model::Plus2A => 13,
             Model::Plus3 => 7,
+            Model::Pentagon => 9,
+            Model::Tc2048 => 14,
         };
         //memory map
-        data[35] = match self.model {
-            Model::Spec48k => 0,
-            Model::Spec128k | Model::Plus3 => self.ula.memory.last_banks(),
+        data[35] = if self.model.is_128k() {
+            self.ula.memory.last_banks()
+        } else {
+            0
         };
-        //36
+        //Timex screen mode
+        data[36] = self.ula.scld.unwrap_or(0);
         data[37] = 3 | // R emulation | LDIR emulation
-                   (if self.model == Model::Spec48k && self.ula.psg.is_some() { 4 } else { 0 }); //PSG in 48k
+                   (if self.model == Model::Spec48k && self.ula.psg.is_some() { 4 } else { 0 }) | //PSG in 48k
+                   (if self.model == Model::Spec16k { 0x80 } else { 0 }); //16K in 48k
+
+        //There is room for just one PSG, the second chip of the TurboSound is not saved and the
+        //first one is selected when loading
         if let Some(ref psg) = self.ula.psg {
             psg.snapshot(&mut data[38..55]);
         }
@@ -770,20 +1528,21 @@ impl<GUI: Gui> Game<GUI> {
             data[start + 1] = (len >> 8) as u8;
         }
 
-        match self.model {
-            Model::Spec48k => {
-                for i in 1..4 {
-                    if let Some(bank) = self.ula.memory.get_bank(i) {
-                        compress(&mut data, [0, 8, 4, 5][i], bank);
-                    }
+        if self.model.is_128k() {
+            for i in 0..8 {
+                if let Some(bank) = self.ula.memory.get_bank(i) {
+                    // 3 first banks are ROM, do not save those
+                    compress(&mut data, i as u8 + 3, bank);
                 }
             }
-            Model::Spec128k | Model::Plus3 => {
-                for i in 0..8 {
-                    if let Some(bank) = self.ula.memory.get_bank(i) {
-                        // 3 first banks are ROM, do not save those
-                        compress(&mut data, i as u8 + 3, bank);
-                    }
+        } else if self.model == Model::Spec16k {
+            if let Some(bank) = self.ula.memory.get_bank(1) {
+                compress(&mut data, 8, bank);
+            }
+        } else {
+            for i in 1..4 {
+                if let Some(bank) = self.ula.memory.get_bank(i) {
+                    compress(&mut data, [0, 8, 4, 5][i], bank);
                 }
             }
         }
@@ -805,16 +1564,33 @@ impl<GUI: Gui> Game<GUI> {
                     rzx::Block::Snapshot(ss) => {
                         data = Cow::Owned(ss.data);
                     }
-                    rzx::Block::Input(input) => {
-                        if !input.frames.is_empty() {
-                            rzx_input = Some(input.frames);
-                        }
+                    rzx::Block::Input(input) if !input.frames.is_empty() => {
+                        rzx_input = Some(input.frames);
                     }
                     _ => {}
                 }
             }
         }
 
+        //SNA snapshots have no signature, they are told apart by their length
+        let mut game = match data.len() {
+            SNA_48K_LEN | SNA_128K_LEN | SNA_128K_LEN_BIG => Self::load_sna(&data)?,
+            _ => Self::load_z80(&data)?,
+        };
+
+        game.ula.rzx_info = rzx_input.map(|frames| RzxInfo {
+            frames,
+            frame_idx: 0,
+            frame_data_idx: 0,
+            in_idx: 0,
+        });
+        if game.ula.rzx_info.is_some() {
+            gui.on_rzx_running(true, 0);
+        }
+        Ok(game)
+    }
+
+    fn load_z80(data: &[u8]) -> Result<Game<GUI>> {
         let file_too_short_error = || anyhow!("invalid z80 format: file too short");
         let data_z80 = data.get(..34).ok_or_else(file_too_short_error)?;
         let (z80, version) = Z80::load_snapshot(data_z80)?;
@@ -842,35 +1618,51 @@ impl<GUI: Gui> Game<GUI> {
             Z80FileVersion::V1 => Model::Spec48k,
             Z80FileVersion::V2 => {
                 match hdr[34] {
+                    0 if hdr[37] & 0x80 != 0 => Model::Spec16k,
                     0 => Model::Spec48k,
+                    3 if hdr[37] & 0x80 != 0 => Model::Plus2,
                     3 => Model::Spec128k,
+                    7 if hdr[37] & 0x80 != 0 => Model::Plus2A,
                     7 => Model::Plus3,
-                    13 => Model::Plus3,
+                    9 => Model::Pentagon,
+                    12 => Model::Plus2,
+                    13 => Model::Plus2A,
+                    14 => Model::Tc2048,
                     _ => Model::Spec128k, //if in doubt, assume 128k
                 }
             }
             Z80FileVersion::V3(_) => {
                 match hdr[34] {
+                    0 if hdr[37] & 0x80 != 0 => Model::Spec16k,
                     0 => Model::Spec48k,
+                    4 if hdr[37] & 0x80 != 0 => Model::Plus2,
                     4 => Model::Spec128k,
-                    7 => Model::Plus3,
-                    13 => Model::Plus3,
+                    7 | 8 if hdr[37] & 0x80 != 0 => Model::Plus2A,
+                    7 | 8 => Model::Plus3,
+                    9 => Model::Pentagon,
+                    12 => Model::Plus2,
+                    13 => Model::Plus2A,
+                    14 => Model::Tc2048,
                     _ => Model::Spec128k, //if in doubt, assume 128k
                 }
             }
         };
-        let psg =
-            if (version != Z80FileVersion::V1 && (hdr[37] & 4) != 0) || model != Model::Spec48k {
-                Some(Psg::load_snapshot(&hdr[38..55]))
-            } else {
-                None
-            };
+        let psg = if (version != Z80FileVersion::V1 && (hdr[37] & 4) != 0) || model.is_128k() {
+            let timing = Timing::for_model(model);
+            Some(Psg::load_snapshot(
+                &hdr[38..55],
+                timing.psg_clock,
+                timing.cpu_freq,
+            ))
+        } else {
+            None
+        };
 
         log::debug!("machine = {:?}", model);
         log::debug!("PSG = {}", psg.is_some());
 
         let mut memory = Memory::new_from_model(model);
-        if model != Model::Spec48k {
+        if model.is_128k() {
             // port 0x7ffd, 0x1ffd
             let v1 = hdr[35];
             let v2 = if version == Z80FileVersion::V3(true) {
@@ -955,7 +1747,11 @@ impl<GUI: Gui> Game<GUI> {
                         .ok_or_else(|| anyhow!("invalid compressed memory block"))?;
                     offset += memlen;
 
-                    let ibank = match (model != Model::Spec48k, page) {
+                    if model == Model::Spec16k && page != 8 {
+                        //some emulators save the missing RAM anyway
+                        continue;
+                    }
+                    let ibank = match (model.is_128k(), page) {
                         (false, 8) => 1,
                         (false, 4) => 2,
                         (false, 5) => 3,
@@ -991,22 +1787,76 @@ impl<GUI: Gui> Game<GUI> {
         // The floppy drive registers are not stored in the snapshot, as far as I know.
         let floppy = match model {
             Model::Plus3 => Some(Floppy::new()),
-            Model::Spec48k | Model::Spec128k => None,
+            Model::Spec16k
+            | Model::Spec48k
+            | Model::Spec128k
+            | Model::Plus2
+            | Model::Plus2A
+            | Model::Pentagon
+            | Model::Tc2048 => None,
         };
         let mut game = Game::from_parts(model, z80, memory, border, psg, floppy);
-
-        game.ula.rzx_info = rzx_input.map(|frames| RzxInfo {
-            frames,
-            frame_idx: 0,
-            frame_data_idx: 0,
-            in_idx: 0,
-        });
-        if game.ula.rzx_info.is_some() {
-            gui.on_rzx_running(true, 0);
+        if model == Model::Tc2048 {
+            game.ula.scld = Some(hdr[36]);
         }
         Ok(game)
     }
 
+    fn load_sna(data: &[u8]) -> Result<Game<GUI>> {
+        let (hdr, ram) = data.split_at(27);
+        let mut z80 = Z80::load_sna(hdr)?;
+        let border = hdr[26] & 7;
+        let mut regs = z80.registers();
+        let (model, mut memory) = if data.len() == SNA_48K_LEN {
+            //A 16K has nothing above 0x8000, and its stack, with the PC, must be below it
+            let spec16k = regs.sp < 0x7fff && ram[0x4000..].iter().all(|&b| b == 0);
+            let model = if spec16k {
+                Model::Spec16k
+            } else {
+                Model::Spec48k
+            };
+            (model, Memory::new_from_model(model))
+        } else {
+            //the 7ffd port is after the PC
+            let mut memory = Memory::new_from_model(Model::Spec128k);
+            memory.restore_banks(ram[0xc002], 0);
+            (Model::Spec128k, memory)
+        };
+        //the first 48K are what is mapped from 0x4000
+        for (i, chunk) in ram[..0xc000].chunks_exact(0x4000).enumerate() {
+            let addr = 0x4000 * (i as u16 + 1);
+            if !memory.is_mapped(addr) {
+                continue;
+            }
+            for (a, &b) in (addr..=addr + 0x3fff).zip(chunk) {
+                memory.poke(a, b);
+            }
+        }
+        let psg = if model.is_128k() {
+            //the PC and the paging are after the first 48K, then the rest of the banks in order
+            regs.pc = u16::from_le_bytes([ram[0xc000], ram[0xc001]]);
+            let paged = usize::from(ram[0xc002] & 0x07);
+            let banks = (0..8).filter(|&b| b != 5 && b != 2 && b != paged);
+            for (ibank, chunk) in banks.zip(ram[0xc004..].chunks_exact(0x4000)) {
+                let bank = memory
+                    .get_bank_mut(ibank)
+                    .ok_or_else(|| anyhow!("invalid snapshot memory"))?;
+                bank.copy_from_slice(chunk);
+            }
+            let timing = Timing::for_model(model);
+            Some(Psg::new(timing.psg_clock, timing.cpu_freq))
+        } else {
+            //a 48K snapshot is restored with a RETN
+            let sp = regs.sp;
+            regs.pc =
+                u16::from(memory.peek(sp)) | (u16::from(memory.peek(sp.wrapping_add(1))) << 8);
+            regs.sp = sp.wrapping_add(2);
+            None
+        };
+        z80.set_registers(&regs);
+        Ok(Game::from_parts(model, z80, memory, border, psg, None))
+    }
+
     pub fn load_disk(&mut self, data: &[u8]) -> Result<()> {
         let Some(floppy) = self.ula.floppy.as_mut() else {
             bail!("Floppy drive not available")
@@ -1037,14 +1887,16 @@ fn snapshot_from_zip(data: &[u8]) -> Result<Vec<u8>> {
         let mut ze = zip.by_index(i)?;
         let name = ze.name();
         let lowname = name.to_ascii_lowercase();
-        if lowname.ends_with(".z80") || lowname.ends_with(".rzx") {
+        if lowname.ends_with(".z80") || lowname.ends_with(".sna") || lowname.ends_with(".rzx") {
             log::debug!("unzipping Z80 {name}");
             let mut res = Vec::new();
             ze.read_to_end(&mut res)?;
             return Ok(res);
         }
     }
-    Err(anyhow!("ZIP file does not contain any *.z80 or *.rzx file"))
+    Err(anyhow!(
+        "ZIP file does not contain any *.z80, *.sna or *.rzx file"
+    ))
 }
 
 #[cfg(not(feature = "zip"))]
@@ -1074,3 +1926,620 @@ fn disk_from_zip(data: &[u8]) -> Result<Vec<u8>> {
 fn disk_from_zip(_data: &[u8]) -> Result<Vec<u8>> {
     Err(anyhow!("ZIP format not supported"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NullGui;
+
+    impl Gui for NullGui {
+        type Pixel = u8;
+        const PALETTE: [[u8; 8]; 2] = [[0, 1, 2, 3, 4, 5, 6, 7], [8, 9, 10, 11, 12, 13, 14, 15]];
+        //GRB332, as ULAplus
+        fn rgb(r: u8, g: u8, b: u8) -> u8 {
+            (g & 0xe0) | ((r & 0xe0) >> 3) | (b >> 6)
+        }
+        fn on_rzx_running(&mut self, _running: bool, _percent: u32) {}
+        fn on_tape_block(&mut self, _index: usize) {}
+        fn put_sound_data(&mut self, _data: &[f32]) {}
+        fn put_image_data(&mut self, _w: usize, _h: usize, _data: &[u8]) {}
+    }
+
+    // LD A,$42 ; LD ($9000),A ; OUT ($FE),A ; LD A,($9000) ; JR $
+    static PROG: &[u8] = &[
+        0x3e, 0x42, 0x32, 0x00, 0x90, 0xd3, 0xfe, 0x3a, 0x00, 0x90, 0x18, 0xfe,
+    ];
+
+    fn game() -> Game<NullGui> {
+        game_model(Model::Spec48k)
+    }
+
+    // Converts a V3 snapshot with the 0x1ffd byte into a V2 one, with the same hardware mode
+    fn z80_v2(v3: &[u8]) -> Vec<u8> {
+        let mut v2 = v3[..55].to_vec();
+        v2[30] = 23;
+        v2.extend_from_slice(&v3[87..]);
+        v2
+    }
+
+    fn game_model(model: Model) -> Game<NullGui> {
+        let mut game = Game::new(model, &mut NullGui);
+        for (i, b) in PROG.iter().enumerate() {
+            game.poke(0x8000 + i as u16, *b);
+        }
+        let mut regs = game.registers();
+        regs.pc = 0x8000;
+        regs.sp = 0xff00;
+        regs.iff1 = false;
+        regs.iff2 = false;
+        game.set_registers(&regs);
+        game
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut game = game();
+        game.add_watch(Watch::Exec(0x8005));
+        assert_eq!(
+            game.draw_frame(false, &mut NullGui),
+            Some(StopReason::Breakpoint(0x8005))
+        );
+        assert_eq!(game.registers().pc, 0x8005);
+        // Resuming runs the instruction at the breakpoint
+        assert_eq!(game.draw_frame(false, &mut NullGui), None);
+        assert!(game.remove_watch(Watch::Exec(0x8005)));
+        assert!(!game.remove_watch(Watch::Exec(0x8005)));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut game = game();
+        game.add_watch(Watch::Write {
+            addr: 0x9000,
+            page: Some(2),
+        });
+        game.add_watch(Watch::Out {
+            port: 0x00fe,
+            mask: 0x00ff,
+        });
+        game.add_watch(Watch::Read {
+            addr: 0x9000,
+            page: None,
+        });
+        // Opcode fetches are not reads
+        game.add_watch(Watch::Read {
+            addr: 0x8000,
+            page: None,
+        });
+        assert_eq!(
+            game.draw_frame(false, &mut NullGui),
+            Some(StopReason::Write(0x9000, 0x42))
+        );
+        assert_eq!(game.registers().pc, 0x8005);
+        assert_eq!(
+            game.draw_frame(false, &mut NullGui),
+            Some(StopReason::Out(0x42fe, 0x42))
+        );
+        assert_eq!(
+            game.draw_frame(false, &mut NullGui),
+            Some(StopReason::Read(0x9000, 0x42))
+        );
+        assert_eq!(game.draw_frame(false, &mut NullGui), None);
+
+        // A page that is not mapped there
+        let mut game = self::game();
+        game.add_watch(Watch::Write {
+            addr: 0x9000,
+            page: Some(5),
+        });
+        assert_eq!(game.draw_frame(false, &mut NullGui), None);
+    }
+
+    #[test]
+    fn contention() {
+        // LD A,($4000) reads memory 10 T-states after the instruction starts
+        let code = [0x3a, 0x00, 0x40];
+        // The ULA delays 6,5,4,3,2,1,0,0 and the +2A gate array 1,0,7,6,5,4,3,2
+        for (model, delays) in [
+            (Model::Spec48k, [6, 0, 0, 6]),
+            (Model::Spec128k, [6, 0, 0, 6]),
+            (Model::Plus2A, [1, 3, 0, 1]),
+        ] {
+            let timing = Timing::for_model(model);
+            let start = timing.contention_start - 10;
+            for (time, d) in [start, start + 6, start + 128, start + timing.line]
+                .into_iter()
+                .zip(delays)
+            {
+                let mut game = game_model(model);
+                for (i, b) in code.iter().enumerate() {
+                    game.poke(0x8000 + i as u16, *b);
+                }
+                game.ula.time = time;
+                assert_eq!(game.step_instruction(&mut NullGui), 13 + d, "{model:?}");
+            }
+        }
+        // OUT ($FE),A with A=0: the ULA port is contended after 1 T-state of the IO cycle
+        let mut game = game();
+        game.poke(0x8000, 0xd3);
+        game.poke(0x8001, 0xfe);
+        let mut regs = game.registers();
+        regs.af = 0;
+        game.set_registers(&regs);
+        game.ula.time = Timing::for_model(Model::Spec48k).contention_start - 8;
+        assert_eq!(game.step_instruction(&mut NullGui), 11 + 6);
+        // The +2A does not contend IO, even with the port in contended memory
+        let mut game = game_model(Model::Plus2A);
+        game.poke(0x8000, 0xd3);
+        game.poke(0x8001, 0xfe);
+        let mut regs = game.registers();
+        regs.af = 0x4000;
+        game.set_registers(&regs);
+        game.ula.time = Timing::for_model(Model::Plus2A).contention_start - 8;
+        assert_eq!(game.step_instruction(&mut NullGui), 11);
+        // INC BC puts IR in the address bus for 2 internal cycles, the ULA delays them but the
+        // +2A gate array does not
+        for (model, t) in [(Model::Spec48k, 6 + 6), (Model::Plus2A, 6)] {
+            let mut game = game_model(model);
+            game.poke(0x8000, 0x03);
+            let mut regs = game.registers();
+            regs.i = 0x40;
+            game.set_registers(&regs);
+            game.ula.time = Timing::for_model(model).contention_start - 4;
+            assert_eq!(game.step_instruction(&mut NullGui), t, "{model:?}");
+        }
+    }
+
+    #[test]
+    fn frame_length() {
+        for (model, frame) in [(Model::Spec48k, 69888), (Model::Spec128k, 70908)] {
+            // JR $ runs forever without contention
+            let mut game = game_model(model);
+            game.poke(0x8000, 0x18);
+            game.poke(0x8001, 0xfe);
+            let mut t = 0;
+            while game.ula.frame_counter == 0 {
+                t += game.step_instruction(&mut NullGui);
+            }
+            assert!((frame..frame + 12).contains(&(t as i32)));
+        }
+    }
+
+    #[test]
+    fn int_window() {
+        for (model, int_length) in [(Model::Spec48k, 32), (Model::Spec128k, 36)] {
+            for late in [false, true] {
+                // JR $ with interrupts disabled until the end of the frame
+                let mut game = game_model(model);
+                game.poke(0x8000, 0x18);
+                game.poke(0x8001, 0xfe);
+                while game.ula.frame_counter == 0 {
+                    game.step_instruction(&mut NullGui);
+                }
+                if late {
+                    while game.ula.time < int_length {
+                        game.step_instruction(&mut NullGui);
+                    }
+                }
+                let mut regs = game.registers();
+                regs.iff1 = true;
+                regs.iff2 = true;
+                game.set_registers(&regs);
+                game.step_instruction(&mut NullGui);
+                let pc = game.registers().pc;
+                assert_eq!(pc, if late { 0x8000 } else { 0x0038 });
+            }
+        }
+    }
+
+    #[test]
+    fn border_changes() {
+        // JR $ until the top border, then LD A,2 ; OUT ($FE),A ; LD A,5 ; OUT ($FE),A ; JR $
+        let mut game = game();
+        game.poke(0x8000, 0x18);
+        game.poke(0x8001, 0xfe);
+        let prog = [0x3e, 0x02, 0xd3, 0xfe, 0x3e, 0x05, 0xd3, 0xfe, 0x18, 0xfe];
+        for (i, b) in prog.iter().enumerate() {
+            game.poke(0x8100 + i as u16, *b);
+        }
+        let line = game.ula.timing.line;
+        let m = game.margins;
+        let first_row = game.ula.timing.first_line - m.top as i32;
+        while game.ula.time < (first_row + 1) * line + 20 {
+            game.step_instruction(&mut NullGui);
+        }
+        let mut regs = game.registers();
+        regs.pc = 0x8100;
+        game.set_registers(&regs);
+        game.draw_frame(false, &mut NullGui);
+
+        let row = |y: usize| &game.image[m.width() * y..m.width() * (y + 1)];
+        assert!(row(0).iter().all(|&p| p == 0));
+        assert!(row(2).iter().all(|&p| p == 5));
+        // Both changes are in the middle of the second row, aligned to groups of 8 pixels
+        let mut colors = vec![row(1)[0]];
+        for (x, w) in row(1).windows(2).enumerate() {
+            if w[0] != w[1] {
+                assert_eq!((x + 1 - m.left) % 8, 0);
+                colors.push(w[1]);
+            }
+        }
+        assert_eq!(colors, [0, 2, 5]);
+    }
+
+    #[test]
+    fn attribute_changes() {
+        for model in [Model::Spec48k, Model::Spec128k] {
+            // JR $ until the first cell of the screen is read, then
+            // LD A,$10 ; LD ($5800),A ; JR $
+            let mut game = game_model(model);
+            game.poke(0x8000, 0x18);
+            game.poke(0x8001, 0xfe);
+            let prog = [0x3e, 0x10, 0x32, 0x00, 0x58, 0x18, 0xfe];
+            for (i, b) in prog.iter().enumerate() {
+                game.poke(0x8100 + i as u16, *b);
+            }
+            // White paper, no ink
+            game.poke(0x5800, 0x38);
+            while game.ula.time < game.ula.timing.contention_start + 8 {
+                game.step_instruction(&mut NullGui);
+            }
+            let mut regs = game.registers();
+            regs.pc = 0x8100;
+            game.set_registers(&regs);
+            game.draw_frame(false, &mut NullGui);
+
+            // The first row of pixels was already read, the second one has red paper
+            let m = game.margins;
+            assert_eq!(game.image[m.width() * m.top + m.left], 7);
+            assert_eq!(game.image[m.width() * (m.top + 1) + m.left], 2);
+            // Next frame reads the new attribute from the start
+            game.draw_frame(false, &mut NullGui);
+            assert_eq!(game.image[m.width() * m.top + m.left], 2);
+        }
+    }
+
+    #[test]
+    fn border_sizes() {
+        let sizes = [
+            (BorderSize::None, 256, 192),
+            (BorderSize::Small, 266, 200),
+            (BorderSize::Normal, 320, 240),
+            (BorderSize::Full, 352, 296),
+        ];
+        for model in [Model::Spec48k, Model::Spec128k] {
+            for (size, w, h) in sizes {
+                // The program sets a red border and runs for a whole frame
+                let mut game = game_model(model);
+                game.set_border_size(size);
+                game.poke(0x5800, 0x38);
+                game.draw_frame(false, &mut NullGui);
+                game.draw_frame(false, &mut NullGui);
+                let m = game.margins;
+                assert_eq!((m.width(), m.height()), (w, h));
+                assert_eq!(game.image.len(), w * h);
+                // Without border, the corners are the first and last attributes
+                let none = size == BorderSize::None;
+                assert_eq!(game.image[0], if none { 7 } else { 2 });
+                assert_eq!(game.image[w * h - 1], if none { 0 } else { 2 });
+            }
+        }
+    }
+
+    #[test]
+    fn floating_bus() {
+        for model in [Model::Spec48k, Model::Spec128k, Model::Plus3] {
+            let mut game = game_model(model);
+            for (addr, b) in [
+                (0x4000, 1),
+                (0x5800, 2),
+                (0x4001, 3),
+                (0x5801, 4),
+                (0x4100, 5),
+            ] {
+                game.poke(addr, b);
+            }
+            let plus3 = model == Model::Plus3;
+            let port = if plus3 { 0x0ffd } else { 0x40ff };
+            let start = game.ula.timing.contention_start;
+            let line = game.ula.timing.line;
+            let mut read_at = |t: i32, port: u16| {
+                // As if the IO cycle of an IN started at `t`
+                game.ula.time = t;
+                game.ula.cycle = 4;
+                let r = game.ula.read_floating_bus(port);
+                game.ula.cycle = 0;
+                r
+            };
+            assert_eq!(read_at(start - 1, port), 0xff);
+            assert_eq!(read_at(start, port), 1);
+            assert_eq!(read_at(start + 1, port), 2);
+            assert_eq!(read_at(start + 2, port), 3);
+            assert_eq!(read_at(start + 3, port), 4);
+            assert_eq!(read_at(start + 4, port), if plus3 { 4 } else { 0xff });
+            assert_eq!(read_at(start + line, port), 5);
+            assert_eq!(read_at(start + 128, port), 0xff);
+            // Other ports of the +3 do not see it
+            assert_eq!(read_at(start, 0x40ff), if plus3 { 0xff } else { 1 });
+        }
+    }
+
+    #[test]
+    fn ulaplus() {
+        let mut game = game();
+        assert_eq!(game.ula.read_port(0xff3b), 0xff);
+        game.set_ulaplus(true);
+        // First paper of the palette is red, GRB332
+        game.ula.write_port(0xbf3b, 0x08);
+        game.ula.write_port(0xff3b, 0x1c);
+        assert_eq!(game.ula.read_port(0xff3b), 0x1c);
+        // Palette mode
+        game.ula.write_port(0xbf3b, 0x40);
+        game.ula.write_port(0xff3b, 0x01);
+        assert_eq!(game.ula.read_port(0xff3b), 0x01);
+        game.draw_frame(false, &mut NullGui);
+        let m = game.margins;
+        assert_eq!(game.image[m.width() * m.top + m.left], 0x1c);
+        // The border uses the papers too, PROG sets it to 2
+        game.ula.write_port(0xbf3b, 0x0a);
+        game.ula.write_port(0xff3b, 0x03);
+        game.draw_frame(false, &mut NullGui);
+        assert_eq!(game.image[0], 0x03);
+
+        game.set_ulaplus(false);
+        game.draw_frame(false, &mut NullGui);
+        assert_eq!(game.image[m.width() * m.top + m.left], 0);
+        assert_eq!(game.image[0], 2);
+    }
+
+    #[test]
+    fn timex_modes() {
+        let mut game = game_model(Model::Tc2048);
+        game.poke(0x4000, 0xf0);
+        game.poke(0x5800, 0x38);
+        game.poke(0x6000, 0x0f);
+        game.poke(0x7800, 0x10);
+        let modes: [(u8, [u8; 4]); 4] = [
+            // Normal: black ink, white paper
+            (0x00, [0, 0, 7, 7]),
+            // Second screen: black ink, red paper
+            (0x01, [2, 2, 0, 0]),
+            // Hi-colour, the attribute of the row is at 0x6000: white ink, blue paper
+            (0x02, [7, 7, 1, 1]),
+            // Hi-res, two bitmaps: blue ink, yellow paper
+            (0x0e, [1, 6, 6, 1]),
+        ];
+        for (mode, pixels) in modes {
+            game.ula.write_port(0x00ff, mode);
+            assert_eq!(game.ula.read_port(0x00ff), mode);
+            game.draw_frame(false, &mut NullGui);
+            let m = game.margins;
+            assert_eq!(m.width(), 2 * 266);
+            let start = m.width() * m.top + m.screen_x();
+            let row: Vec<u8> = (0..4).map(|i| game.image[start + 4 * i]).collect();
+            assert_eq!(row, pixels, "mode {mode:02x}");
+        }
+
+        let snapshot = game.snapshot();
+        for snapshot in [z80_v2(&snapshot), snapshot] {
+            let game = Game::<NullGui>::load_snapshot(&snapshot, &mut NullGui).unwrap();
+            assert_eq!(game.model(), Model::Tc2048);
+            assert_eq!(game.ula.scld, Some(0x0e));
+        }
+    }
+
+    #[test]
+    fn snow() {
+        for (snow, ir) in [(true, 0x4005), (false, 0x4005), (true, 0x8005)] {
+            let mut game = game();
+            game.set_snow(snow);
+            for (addr, b) in [(0x4000, 1), (0x5800, 2), (0x4005, 3), (0x5805, 4)] {
+                game.poke(addr, b);
+            }
+            // A refresh with the bitmap and the attribute of the first cell in its 2 T-states
+            game.ula.time = game.ula.timing.contention_start + 5;
+            game.ula.refresh(ir);
+            game.ula.fetch_screen();
+            let cell = &game.ula.screen.cells[0];
+            let corrupted = snow && ir == 0x4005;
+            assert_eq!(
+                (cell.bitmap, cell.attr),
+                if corrupted { (3, 4) } else { (1, 2) }
+            );
+        }
+    }
+
+    #[test]
+    fn spec16k() {
+        // LD A,($8000) during the screen reads the floating bus
+        let mut game = game_model(Model::Spec16k);
+        game.poke(0x8000, 0x12);
+        assert_eq!(game.peek(0x8000), 0xff);
+        let code = [0x3a, 0x00, 0x80];
+        for (i, b) in code.iter().enumerate() {
+            game.poke(0x6000 + i as u16, *b);
+        }
+        game.poke(0x4000, 0x55);
+        let mut regs = game.registers();
+        regs.pc = 0x6000;
+        game.set_registers(&regs);
+        // The memory read ends when the ULA reads the first bitmap byte
+        game.ula.time = game.ula.timing.contention_start + 4 - 13;
+        game.step_instruction(&mut NullGui);
+        assert_eq!(game.registers().af >> 8, 0x55);
+
+        game.poke(0x7fff, 0x34);
+        let snapshot = game.snapshot();
+        let mut game = Game::<NullGui>::load_snapshot(&snapshot, &mut NullGui).unwrap();
+        assert_eq!(game.model(), Model::Spec16k);
+        assert_eq!(game.peek(0x7fff), 0x34);
+    }
+
+    #[test]
+    fn sna() {
+        // HL=0x1234, SP=0x7000, IM 1, border blue
+        let mut sna = vec![0; SNA_48K_LEN];
+        sna[9..11].copy_from_slice(&[0x34, 0x12]);
+        sna[23..25].copy_from_slice(&[0x00, 0x70]);
+        sna[25] = 1;
+        sna[26] = 1;
+        // PC=0x6000 in the stack
+        sna[27 + 0x3000..27 + 0x3002].copy_from_slice(&[0x00, 0x60]);
+        let game = Game::<NullGui>::load_snapshot(&sna, &mut NullGui).unwrap();
+        assert_eq!(game.model(), Model::Spec16k);
+        let regs = game.registers();
+        assert_eq!((regs.pc, regs.sp, regs.hl), (0x6000, 0x7002, 0x1234));
+        assert_eq!(regs.im, z80::InterruptMode::IM1);
+        assert_eq!(game.ula.border, 1);
+
+        // Something above 0x8000 is a 48K
+        sna[27 + 0x8000] = 0x55;
+        let mut game = Game::<NullGui>::load_snapshot(&sna, &mut NullGui).unwrap();
+        assert_eq!(game.model(), Model::Spec48k);
+        assert_eq!(game.peek(0xc000), 0x55);
+
+        // A 128K with bank 1 paged, and PC=0x8000 after the first 48K
+        let mut sna = vec![0; SNA_128K_LEN];
+        sna[27 + 0x4000] = 0x22;
+        sna[27 + 0x8000] = 0x11;
+        sna[SNA_48K_LEN..SNA_48K_LEN + 3].copy_from_slice(&[0x00, 0x80, 0x01]);
+        // The banks 0, 3, 4, 6 and 7 follow
+        sna[SNA_48K_LEN + 4 + 0x4000] = 0x33;
+        let mut game = Game::<NullGui>::load_snapshot(&sna, &mut NullGui).unwrap();
+        assert_eq!(game.model(), Model::Spec128k);
+        assert_eq!(game.registers().pc, 0x8000);
+        assert_eq!(game.peek(0x8000), 0x22);
+        assert_eq!(game.peek(0xc000), 0x11);
+        game.ula.write_port(0x7ffd, 0x03);
+        assert_eq!(game.peek(0xc000), 0x33);
+    }
+
+    #[test]
+    fn plus2_models() {
+        for model in [Model::Spec128k, Model::Plus2, Model::Plus2A, Model::Plus3] {
+            // All RAM mode: page 0 at 0x0000
+            let mut game = game_model(model);
+            game.poke(0xc000, 0x12);
+            game.ula.write_port(0x1ffd, 0x01);
+            let all_ram = game.peek(0x0000) == 0x12;
+            assert_eq!(all_ram, model != Model::Plus2, "{model:?}");
+            assert_eq!(game.ula.floppy.is_some(), model == Model::Plus3);
+
+            let snapshot = game.snapshot();
+            let game = Game::<NullGui>::load_snapshot(&snapshot, &mut NullGui).unwrap();
+            assert_eq!(game.model(), model);
+        }
+    }
+
+    #[test]
+    fn pentagon() {
+        let mut game = game_model(Model::Pentagon);
+        // JR $ runs for a whole frame
+        game.poke(0x8000, 0x18);
+        game.poke(0x8001, 0xfe);
+        let mut t = 0;
+        while game.ula.frame_counter == 0 {
+            t += game.step_instruction(&mut NullGui);
+        }
+        assert!((71680..71680 + 12).contains(&(t as i32)));
+        // LD A,($4000) ; OUT ($FE),A in the middle of the screen are not delayed
+        let code = [0x3a, 0x00, 0x40, 0xd3, 0xfe];
+        for (i, b) in code.iter().enumerate() {
+            game.poke(0x8000 + i as u16, *b);
+        }
+        game.ula.time = game.ula.timing.contention_start;
+        let mut regs = game.registers();
+        regs.pc = 0x8000;
+        game.set_registers(&regs);
+        assert_eq!(game.step_instruction(&mut NullGui), 13);
+        assert_eq!(game.step_instruction(&mut NullGui), 11);
+        // No floating bus either
+        assert_eq!(game.ula.read_port(0x40ff), 0xff);
+
+        // The full border is shorter at the bottom
+        game.set_border_size(BorderSize::Full);
+        assert_eq!((game.margins.width(), game.margins.height()), (352, 288));
+
+        let snapshot = game.snapshot();
+        for snapshot in [z80_v2(&snapshot), snapshot] {
+            let game = Game::<NullGui>::load_snapshot(&snapshot, &mut NullGui).unwrap();
+            assert_eq!(game.model(), Model::Pentagon);
+        }
+    }
+
+    #[test]
+    fn pentagon_full_border() {
+        let mut game = game_model(Model::Pentagon);
+        game.set_border_size(BorderSize::Full);
+        // PROG sets a red border, the last row of the image ends after the interrupt
+        game.draw_frame(false, &mut NullGui);
+        let m = game.margins;
+        let t_end = (m.height() + 80 - m.top) as i32 * 224 + 68;
+        assert!(t_end > game.ula.timing.frame);
+        let last_row = &game.image[(m.height() - 1) * m.width()..];
+        assert!(last_row.iter().all(|&p| p == 2));
+    }
+
+    #[test]
+    fn stereo() {
+        let mut game = game_model(Model::Spec128k);
+        // Only channel A, at full volume
+        for (reg, v) in [(0x07, 0x3e), (0x08, 0x0f), (0x09, 0x00), (0x0a, 0x00)] {
+            game.ula.write_port(0xfffd, reg);
+            game.ula.write_port(0xbffd, v);
+        }
+        for (mode, lr) in [
+            (StereoMode::Mono, [8192, 8192]),
+            (StereoMode::Abc, [8192, 0]),
+            (StereoMode::Acb, [8192, 0]),
+            (StereoMode::Custom([0.75, 0.5, 0.5]), [4096, 8192]),
+        ] {
+            game.set_stereo_mode(mode);
+            assert_eq!(game.ula.audio_sample(0), lr, "{mode:?}");
+        }
+        // Channel B is at the right in ACB
+        game.ula.write_port(0xfffd, 0x08);
+        game.ula.write_port(0xbffd, 0x00);
+        game.ula.write_port(0xfffd, 0x09);
+        game.ula.write_port(0xbffd, 0x0f);
+        game.set_stereo_mode(StereoMode::Acb);
+        assert_eq!(game.ula.audio_sample(0), [0, 8192]);
+    }
+
+    #[test]
+    fn turbo_sound() {
+        let mut game = game_model(Model::Spec128k);
+        game.set_turbo_sound(true);
+        game.set_stereo_mode(StereoMode::Abc);
+        fn write(game: &mut Game<NullGui>, regs: [(u8, u8); 4]) {
+            for (reg, v) in regs {
+                game.ula.write_port(0xfffd, reg);
+                game.ula.write_port(0xbffd, v);
+            }
+        }
+        // Only channel A in the first chip, only C in the second one
+        write(
+            &mut game,
+            [(0x07, 0x3f), (0x08, 0x0f), (0x09, 0x00), (0x0a, 0x00)],
+        );
+        game.ula.write_port(0xfffd, 0xfe);
+        write(
+            &mut game,
+            [(0x07, 0x3f), (0x08, 0x00), (0x09, 0x00), (0x0a, 0x0f)],
+        );
+        assert_eq!(game.ula.read_port(0xfffd), 0x0f);
+        assert_eq!(game.ula.audio_sample(0), [8192, 8192]);
+        // Back to the first chip
+        game.ula.write_port(0xfffd, 0xff);
+        game.ula.write_port(0xfffd, 0x0a);
+        assert_eq!(game.ula.read_port(0xfffd), 0x00);
+        assert_eq!(game.psg_status(0).unwrap()[11], 0x00);
+        assert_eq!(game.psg_status(1).unwrap()[11], 0x0f);
+        assert_eq!(game.psg_status(2), None);
+
+        // Without TurboSound, the second chip is not heard
+        game.set_turbo_sound(false);
+        assert_eq!(game.ula.audio_sample(0), [8192, 0]);
+    }
+}
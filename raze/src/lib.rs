@@ -1,3 +1,4 @@
+mod debug;
 mod disk;
 mod floppy;
 mod game;
@@ -6,10 +7,16 @@ mod psg;
 mod rzx;
 mod speaker;
 mod tape;
+mod timing;
+mod ulaplus;
 mod z80;
 
-pub use game::{Game, Gui, Model};
-pub use z80::Z80;
+pub use debug::{StopReason, Watch};
+pub use game::{BorderSize, Game, Gui, Model};
+pub use psg::{PsgChip, StereoMode};
+pub use speaker::SpeakerFilter;
+pub use z80::disasm;
+pub use z80::{Bus, FetchReason, InterruptMode, Registers, Z80};
 
 use std::io::{self, Read};
 
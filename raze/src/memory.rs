@@ -4,6 +4,8 @@ struct Bank {
     data: [u8; 0x4000],
     ro: bool,
     contended: bool,
+    //nothing is connected here, reads return the floating bus
+    mapped: bool,
 }
 impl Bank {
     fn rom(data: &[u8]) -> Bank {
@@ -11,6 +13,7 @@ impl Bank {
             data: data.try_into().expect("rom length != 0x4000"),
             ro: true,
             contended: false,
+            mapped: true,
         }
     }
     fn ram(contended: bool) -> Bank {
@@ -18,6 +21,15 @@ impl Bank {
             data: [0; 0x4000],
             ro: false,
             contended,
+            mapped: true,
+        }
+    }
+    fn unmapped() -> Bank {
+        Bank {
+            data: [0xff; 0x4000],
+            ro: true,
+            contended: false,
+            mapped: false,
         }
     }
 }
@@ -27,7 +39,6 @@ pub struct Memory {
     banks: [usize; 4],
     vram: usize,
     locked: bool,
-    delay: u32,
     last_banks: u8,
     last_banks_plus2: u8,
 }
@@ -42,6 +53,7 @@ static ROM_PLUS3_3: &[u8] = include_bytes!("pl3-3.rom");
 
 #[derive(Copy, Clone)]
 pub enum RomBlob {
+    R16k(&'static [u8]),
     R48k(&'static [u8]),
     R128k(&'static [u8], &'static [u8]),
     Plus3(&'static [u8], &'static [u8], &'static [u8], &'static [u8]),
@@ -50,9 +62,21 @@ pub enum RomBlob {
 impl Memory {
     pub fn new_from_model(model: Model) -> Self {
         match model {
-            Model::Spec48k => Self::new_from_rom(RomBlob::R48k(ROM_48)),
-            Model::Spec128k => Self::new_from_rom(RomBlob::R128k(ROM_128_0, ROM_128_1)),
-            Model::Plus3 => Self::new_from_rom(RomBlob::Plus3(
+            Model::Spec16k => Self::new_from_rom(RomBlob::R16k(ROM_48)),
+            // The TC2048 ROM is almost the same as the 48K one, that is used instead
+            Model::Spec48k | Model::Tc2048 => Self::new_from_rom(RomBlob::R48k(ROM_48)),
+            Model::Spec128k | Model::Plus2 => {
+                Self::new_from_rom(RomBlob::R128k(ROM_128_0, ROM_128_1))
+            }
+            Model::Pentagon => {
+                //the memory of the 128K, without contention
+                let mut memory = Self::new_from_rom(RomBlob::R128k(ROM_128_0, ROM_128_1));
+                for bank in &mut memory.data {
+                    bank.contended = false;
+                }
+                memory
+            }
+            Model::Plus2A | Model::Plus3 => Self::new_from_rom(RomBlob::Plus3(
                 ROM_PLUS3_0,
                 ROM_PLUS3_1,
                 ROM_PLUS3_2,
@@ -63,6 +87,18 @@ impl Memory {
 
     pub fn new_from_rom(rom: RomBlob) -> Self {
         match rom {
+            RomBlob::R16k(rom0) => {
+                //0x8000-0xffff share the same unmapped bank
+                let data = vec![Bank::rom(rom0), Bank::ram(true), Bank::unmapped()];
+                Memory {
+                    data,
+                    banks: [0, 1, 2, 2],
+                    vram: 1,
+                    locked: true,
+                    last_banks: 0,
+                    last_banks_plus2: 0,
+                }
+            }
             RomBlob::R48k(rom0) => {
                 let data = vec![
                     Bank::rom(rom0),
@@ -75,7 +111,6 @@ impl Memory {
                     banks: [0, 1, 2, 3],
                     vram: 1,
                     locked: true,
-                    delay: 0,
                     last_banks: 0,
                     last_banks_plus2: 0,
                 }
@@ -98,7 +133,6 @@ impl Memory {
                     banks: [8, 5, 2, 0],
                     vram: 5,
                     locked: false,
-                    delay: 0,
                     last_banks: 0,
                     last_banks_plus2: 0,
                 }
@@ -123,7 +157,6 @@ impl Memory {
                     banks: [8, 5, 2, 0],
                     vram: 5,
                     locked: false,
-                    delay: 0,
                     last_banks: 0,
                     last_banks_plus2: 0,
                 }
@@ -145,21 +178,15 @@ impl Memory {
         (bank, offs)
     }
     #[inline]
-    pub fn peek(&mut self, addr: impl Into<u16>) -> u8 {
-        let (bank, offs) = self.split_addr(addr);
+    pub fn peek(&self, addr: impl Into<u16>) -> u8 {
+        let addr: u16 = addr.into();
+        let bank = self.banks[usize::from(addr >> 14)];
+        // SAFETY: see split_bank.
+        let bank = unsafe { self.data.get_unchecked(bank) };
         // offs is 14 bits, and every bank is 0x4000 bytes long.
         // RAM banks are just created that way (see Bank::ram), while ROM
         // bank length is asserted in Memory::new_from_bytes()
-        let res = bank.data[offs];
-        if bank.contended {
-            self.delay += 1;
-        }
-        res
-    }
-    #[inline]
-    pub fn peek_no_delay(&mut self, addr: u16) -> u8 {
-        let (bank, offs) = self.split_addr(addr);
-        bank.data[offs]
+        bank.data[usize::from(addr & 0x3fff)]
     }
     #[inline]
     pub fn poke(&mut self, addr: impl Into<u16>, data: u8) {
@@ -169,15 +196,40 @@ impl Memory {
             return;
         }
         bank.data[offs] = data;
-        if bank.contended {
-            self.delay += 1;
+    }
+    // Accesses to this address are delayed by the ULA
+    #[inline]
+    pub fn is_contended(&self, addr: u16) -> bool {
+        let bank = self.banks[usize::from(addr >> 14)];
+        self.data[bank].contended
+    }
+    // There is something at `addr`, RAM or ROM
+    #[inline]
+    pub fn is_mapped(&self, addr: u16) -> bool {
+        let bank = self.banks[usize::from(addr >> 14)];
+        self.data[bank].mapped
+    }
+    // The RAM page (0..7) mapped at `addr`, with the 128K numbering. The 48K RAM is numbered as
+    // the pages mapped by default in a 128K: 5, 2, 0. ROM has no page.
+    pub fn ram_page(&self, addr: u16) -> Option<u8> {
+        let ibank = self.banks[usize::from(addr >> 14)];
+        if self.data[ibank].ro {
+            return None;
+        }
+        if self.data.len() <= 4 {
+            [None, Some(5), Some(2), Some(0)][ibank]
+        } else {
+            Some(ibank as u8)
         }
     }
-    pub fn take_delay(&mut self) -> u32 {
-        std::mem::take(&mut self.delay)
+    // Writing to this address may change the screen being displayed
+    #[inline]
+    pub fn is_video(&self, addr: u16) -> bool {
+        self.banks[usize::from(addr >> 14)] == self.vram
     }
+    // The whole bank read by the ULA, the Timex second screen is at offset 0x2000
     pub fn video_memory(&self) -> &[u8] {
-        &self.data[self.vram].data[..32 * 192 + 32 * 24]
+        &self.data[self.vram].data
     }
 
     // Use this to load a snapshot
@@ -255,6 +307,10 @@ impl Memory {
         self.update_banks();
     }
 
+    // Paging is disabled until the next reset
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
     pub fn last_banks(&self) -> u8 {
         self.last_banks
     }
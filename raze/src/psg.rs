@@ -1,4 +1,7 @@
 //Emulation of the AY-3-8910 programmable sound generator
+//
+//The generators count cycles of the PSG clock, that is independent of the CPU clock. The tone
+//and noise periods are 16 cycles per unit of the frequency registers.
 
 struct FreqGen {
     divisor: u32,
@@ -8,12 +11,12 @@ struct FreqGen {
 impl FreqGen {
     fn new() -> FreqGen {
         FreqGen {
-            divisor: 32,
+            divisor: 16,
             phase: 0,
         }
     }
     fn set_freq(&mut self, freq: u16) {
-        self.divisor = 32 * u32::from(freq);
+        self.divisor = 16 * u32::from(freq);
     }
     fn next_sample(&mut self, t: u32) -> bool {
         self.phase += t;
@@ -34,14 +37,14 @@ struct NoiseGen {
 impl NoiseGen {
     fn new() -> NoiseGen {
         NoiseGen {
-            divisor: 32,
+            divisor: 16,
             shift: 1,
             level: false,
             phase: 0,
         }
     }
     fn set_freq(&mut self, freq: u8) {
-        self.divisor = 32 * u32::from(freq);
+        self.divisor = 16 * u32::from(freq);
     }
     fn next_sample(&mut self, t: u32) -> bool {
         self.phase += t;
@@ -81,6 +84,8 @@ struct Envelope {
     divisor: u32,
     phase: u32,
     shape: EnvShape,
+    //steps in every ramp: 16 in the AY-3-8910, 32 in the YM2149
+    steps: u8,
     step: u8,
     block: EnvBlock,
 }
@@ -88,8 +93,9 @@ struct Envelope {
 impl Envelope {
     fn new() -> Envelope {
         Envelope {
-            divisor: 32,
+            divisor: 16,
             phase: 0,
+            steps: 16,
             step: 0,
             shape: EnvShape::LowerLow,
             block: EnvBlock::Low,
@@ -97,7 +103,8 @@ impl Envelope {
     }
     fn set_freq_shape(&mut self, freq: u16, shape: u8) {
         use self::{EnvBlock::*, EnvShape::*};
-        self.divisor = 32 * u32::from(freq);
+        //a whole ramp takes 256 cycles per unit of frequency
+        self.divisor = 256 * u32::from(freq) / u32::from(self.steps);
         self.phase = 0;
         self.step = 0;
         let (shape, block) = match shape {
@@ -121,7 +128,7 @@ impl Envelope {
         while self.phase > self.divisor {
             self.phase -= self.divisor;
             self.step += 1;
-            if self.step == 16 {
+            if self.step == self.steps {
                 self.step = 0;
 
                 self.block = match self.shape {
@@ -139,9 +146,9 @@ impl Envelope {
         }
         match self.block {
             Low => 0,
-            High => 15,
+            High => self.steps - 1,
             Raise => self.step,
-            Lower => 15 - self.step,
+            Lower => self.steps - 1 - self.step,
         }
     }
 }
@@ -173,8 +180,66 @@ static REG_MASK: [u8; 16] = [
     /*0x0e      */ 0xff, /*0x0f      */ 0xff,
 ];
 
+/// The PSG chip emulated, they differ in the volume levels and the envelope resolution
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum PsgChip {
+    /// The original General Instrument AY-3-8910
+    #[default]
+    Ay8910,
+    /// The Yamaha YM2149 clone
+    Ym2149,
+}
+
+// Measured output levels of the DAC of each chip, scaled so that the maximum is 8192.
+// The AY has 16 levels, the YM has 32: the fixed volumes use the odd ones.
+static AY_LEVELS: [u16; 16] = [
+    0, 82, 118, 173, 252, 373, 528, 880, 1037, 1679, 2394, 3054, 4035, 5205, 6599, 8192,
+];
+static YM_LEVELS: [u16; 32] = [
+    0, 0, 38, 63, 90, 114, 139, 164, 200, 243, 287, 331, 398, 478, 558, 637, 758, 910, 1063, 1216,
+    1447, 1733, 2018, 2303, 2734, 3280, 3829, 4378, 5203, 6210, 7208, 8192,
+];
+
+/// How the three channels are mixed into the left and right outputs
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum StereoMode {
+    /// All the channels in both sides
+    #[default]
+    Mono,
+    /// A left, B center, C right
+    Abc,
+    /// A left, C center, B right
+    Acb,
+    /// Position of the channels A, B and C: 0.0 is left, 0.5 center and 1.0 right
+    Custom([f32; 3]),
+}
+
+impl StereoMode {
+    /// Gains of the left and right outputs for every channel. A centered channel is at full
+    /// volume in both sides, so that the mono mix sounds as loud as before.
+    pub fn gains(self) -> [[f32; 2]; 3] {
+        let pans = match self {
+            StereoMode::Mono => [0.5, 0.5, 0.5],
+            StereoMode::Abc => [0.0, 0.5, 1.0],
+            StereoMode::Acb => [0.0, 1.0, 0.5],
+            StereoMode::Custom(pans) => pans,
+        };
+        pans.map(|p| {
+            let p = p.clamp(0.0, 1.0);
+            [(2.0 * (1.0 - p)).min(1.0), (2.0 * p).min(1.0)]
+        })
+    }
+}
+
 /// The Programmable Sound Generator: AY-3-8910
 pub struct Psg {
+    /// Which chip is emulated
+    chip: PsgChip,
+    /// Frequency of the PSG clock and of the CPU clock, in Hz
+    clock: u64,
+    cpu_freq: u64,
+    /// Fraction of a PSG cycle not run yet, in units of 1/cpu_freq cycles
+    clock_accum: u64,
     /// The selected register, that will be read/written next
     reg_sel: u8,
     /// There are 16 byte-sized registers
@@ -192,8 +257,12 @@ pub struct Psg {
 }
 
 impl Psg {
-    pub fn new() -> Psg {
+    pub fn new(clock: u32, cpu_freq: u32) -> Psg {
         Psg {
+            chip: PsgChip::default(),
+            clock: u64::from(clock),
+            cpu_freq: u64::from(cpu_freq),
+            clock_accum: 0,
             reg_sel: 0,
             reg: [0; 16],
             freq_a: FreqGen::new(),
@@ -203,10 +272,10 @@ impl Psg {
             envelope: Envelope::new(),
         }
     }
-    pub fn load_snapshot(data: &[u8]) -> Psg {
+    pub fn load_snapshot(data: &[u8], clock: u32, cpu_freq: u32) -> Psg {
         // Go through write_reg() to rebuild the state of the inner generators.
         // Generator phases are not restored, but that should be unnoticeable.
-        let mut psg = Self::new();
+        let mut psg = Self::new(clock, cpu_freq);
         for (r, &v) in data[1..17].iter().enumerate() {
             psg.reg_sel = r as u8;
             psg.write_reg(v);
@@ -219,6 +288,35 @@ impl Psg {
         data[0] = self.reg_sel;
         data[1..17].copy_from_slice(&self.reg);
     }
+    pub fn chip(&self) -> PsgChip {
+        self.chip
+    }
+    pub fn set_chip(&mut self, chip: PsgChip) {
+        if chip == self.chip {
+            return;
+        }
+        self.chip = chip;
+        self.envelope.steps = match chip {
+            PsgChip::Ay8910 => 16,
+            PsgChip::Ym2149 => 32,
+        };
+        if chip == PsgChip::Ay8910 {
+            // Drop the bits that the YM kept but the AY does not store
+            for (r, mask) in self.reg.iter_mut().zip(REG_MASK) {
+                *r &= mask;
+            }
+            self.freq_a
+                .set_freq(Self::freq(self.reg[0x00], self.reg[0x01]));
+            self.freq_b
+                .set_freq(Self::freq(self.reg[0x02], self.reg[0x03]));
+            self.freq_c
+                .set_freq(Self::freq(self.reg[0x04], self.reg[0x05]));
+            self.noise.set_freq(self.reg[0x06].max(1));
+        }
+        // Rebuild the envelope for the new resolution
+        let freq = Self::freq(self.reg[0x0b], self.reg[0x0c]);
+        self.envelope.set_freq_shape(freq, self.reg[0x0d]);
+    }
     /// Changes the selected register
     pub fn select_reg(&mut self, reg: u8) {
         if let 0..=0x0f = reg {
@@ -234,7 +332,10 @@ impl Psg {
     /// Writes the selected register
     pub fn write_reg(&mut self, x: u8) {
         let reg_sel = usize::from(self.reg_sel);
-        self.reg[reg_sel] = x & REG_MASK[reg_sel];
+        self.reg[reg_sel] = match self.chip {
+            PsgChip::Ay8910 => x & REG_MASK[reg_sel],
+            PsgChip::Ym2149 => x,
+        };
         //log::info!("PSG write {:02x} <- {:02x}", reg_sel, x);
         // Writing to most registers has a side effect
         match reg_sel {
@@ -274,7 +375,13 @@ impl Psg {
             _ => {}
         }
     }
-    pub fn next_sample(&mut self, t: u32) -> u16 {
+    /// Volume of the channels A, B and C after `t` T-states of the CPU
+    pub fn next_sample(&mut self, t: u32) -> [u16; 3] {
+        // Convert to cycles of the PSG clock
+        self.clock_accum += u64::from(t) * self.clock;
+        let t = (self.clock_accum / self.cpu_freq) as u32;
+        self.clock_accum %= self.cpu_freq;
+
         //Reg 0x07 is a bitmask that _disables_ what is to be mixed to the final output:
         // * 0b0000_0001: do not mix freq_a
         // * 0b0000_0010: do not mix freq_b
@@ -302,31 +409,32 @@ impl Psg {
         // Envelope is computed even if unused
         let env = self.envelope.next_sample(t);
 
-        // Add the enabled channels, pondering the volume and the envelope
-        let mut res: u16 = 0;
+        // The enabled channels, pondering the volume and the envelope
+        let mut res = [0; 3];
         if chan_a {
             let v = self.reg[0x08];
-            let vol = Self::volume(v, env);
-            res += vol;
+            res[0] = self.volume(v, env);
         }
         if chan_b {
             let v = self.reg[0x09];
-            let vol = Self::volume(v, env);
-            res += vol;
+            res[1] = self.volume(v, env);
         }
         if chan_c {
             let v = self.reg[0x0a];
-            let vol = Self::volume(v, env);
-            res += vol;
+            res[2] = self.volume(v, env);
         }
         res
     }
-    fn volume(v: u8, env: u8) -> u16 {
-        let v = if v & 0x10 != 0 { env } else { v & 0x0f };
-        static LEVELS: [u16; 16] = [
-            0, 94, 133, 197, 283, 413, 589, 920, 1096, 1759, 2482, 3142, 4164, 5340, 6669, 8192,
-        ];
-        LEVELS[usize::from(v)]
+    fn volume(&self, v: u8, env: u8) -> u16 {
+        let env_enabled = v & 0x10 != 0;
+        let v = usize::from(v & 0x0f);
+        let env = usize::from(env);
+        match (self.chip, env_enabled) {
+            (PsgChip::Ay8910, false) => AY_LEVELS[v],
+            (PsgChip::Ay8910, true) => AY_LEVELS[env],
+            (PsgChip::Ym2149, false) => YM_LEVELS[2 * v + 1],
+            (PsgChip::Ym2149, true) => YM_LEVELS[env],
+        }
     }
     fn freq(a: u8, b: u8) -> u16 {
         let n = u16::from_le_bytes([a, b]);
@@ -353,3 +461,67 @@ impl Psg {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(psg: &mut Psg, reg: u8, value: u8) {
+        psg.select_reg(reg);
+        psg.write_reg(value);
+    }
+
+    #[test]
+    fn clock() {
+        // Tone A with period 100 is 1773400 / 16 / 100 = 1108 Hz, whatever the CPU clock
+        for cpu_freq in [3_500_000, 3_546_900, 7_000_000] {
+            let mut psg = Psg::new(1_773_400, cpu_freq);
+            write(&mut psg, 0x00, 100);
+            write(&mut psg, 0x07, 0x3e);
+            write(&mut psg, 0x08, 0x0f);
+            let mut edges = 0;
+            let mut last = 0;
+            for _ in 0..cpu_freq / 4 {
+                let a = psg.next_sample(4)[0];
+                if a > last {
+                    edges += 1;
+                }
+                last = a;
+            }
+            assert!((1107..=1109).contains(&edges), "{cpu_freq}: {edges}");
+        }
+    }
+
+    #[test]
+    fn chips() {
+        let mut psg = Psg::new(1_773_450, 3_546_900);
+        write(&mut psg, 0x07, 0x3f);
+        write(&mut psg, 0x08, 0x07);
+        write(&mut psg, 0x09, 0xe0);
+        assert_eq!(psg.next_sample(0), [880, 0, 0]);
+        // The AY keeps only the used bits of the registers
+        assert_eq!(psg.read_reg(), 0x00);
+
+        psg.set_chip(PsgChip::Ym2149);
+        assert_eq!(psg.next_sample(0), [637, 0, 0]);
+        write(&mut psg, 0x09, 0xe0);
+        assert_eq!(psg.read_reg(), 0xe0);
+        // The YM envelope has 32 steps: the lowering ramp starts at the top level
+        write(&mut psg, 0x08, 0x10);
+        write(&mut psg, 0x0b, 0x01);
+        write(&mut psg, 0x0d, 0x00);
+        assert_eq!(psg.next_sample(0)[0], 8192);
+        // 8 PSG cycles per step
+        assert_eq!(psg.next_sample(18)[0], YM_LEVELS[30]);
+
+        // Back to the AY, the registers written in the YM lose the unused bits
+        write(&mut psg, 0x01, 0xf3);
+        write(&mut psg, 0x06, 0xe0);
+        psg.set_chip(PsgChip::Ay8910);
+        for (reg, value) in [(0x01, 0x03), (0x06, 0x00), (0x09, 0x00), (0x0d, 0x00)] {
+            psg.select_reg(reg);
+            assert_eq!(psg.read_reg(), value, "{reg:02x}");
+        }
+        assert_eq!(psg.freq_a.divisor, 16 * 0x300);
+    }
+}
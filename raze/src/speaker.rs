@@ -1,55 +1,230 @@
 /*
- * This module implements a simple mono speaker.
+ * This module implements the audio output, a stereo speaker.
+ *
+ * The input is a level that changes in steps, at any T-state. Every step is added to the output
+ * as a band-limited step, so that the frequencies above the sample rate do not alias. The
+ * integral of a windowed sinc is used as step, that is also the low-pass filter.
  */
 
+use std::f32::consts::PI;
+
+//Length of the band-limited step, in output samples. The output is delayed half of it.
+const STEP_LEN: usize = 32;
+//Resolution of the position of a step between two output samples
+const STEP_PHASES: usize = 32;
+//Ring buffer of the pending output samples, it must be longer than STEP_LEN
+const BUF_LEN: usize = 64;
+
+//Left and right
+const CHANNELS: usize = 2;
+
+//Filters applied to the speaker output
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SpeakerFilter {
+    //cutoff of the low-pass filter, in Hz. It is limited to 0.4 times the sample rate.
+    pub low_pass: f32,
+    //cutoff of the high-pass filter that removes the DC level, in Hz
+    pub high_pass: f32,
+}
+
+impl Default for SpeakerFilter {
+    fn default() -> SpeakerFilter {
+        SpeakerFilter {
+            low_pass: 15000.0,
+            high_pass: 20.0,
+        }
+    }
+}
+
 pub struct Speaker {
+    //interleaved left and right samples
     audio: Vec<f32>,
-    audio_time: u32,
-    audio_accum: u32,
-    t_per_sample: u32,
+    //time since the last sample was output, in units of 1/(cpu_freq * sample_rate) seconds, so
+    //that there is no rounding error in the duration of each sample
+    audio_time: u64,
+    cpu_freq: u64,
+    sample_rate: u64,
+    filter: SpeakerFilter,
+    //impulse response of the low-pass filter, for every phase of the step
+    kernel: Vec<[f32; STEP_LEN]>,
+    //the input level since the last step
+    level: [u32; CHANNELS],
+    //differences between the pending output samples, the next one is at `pos`
+    deltas: [[f32; CHANNELS]; BUF_LEN],
+    pos: usize,
+    //sum of all the differences, that is the band-limited level
+    sum: [f32; CHANNELS],
+    //DC blocker: feedback, previous input and previous output
+    dc_r: f32,
+    dc_x: [f32; CHANNELS],
+    dc_y: [f32; CHANNELS],
 }
 
 impl Speaker {
-    pub fn new(t_per_sample: u32) -> Speaker {
-        /* This is the freq. of the CPU divided by the freq. of the audio sample frequency: 22050 Hz
-         * 48k model runs at 3.5 MHz
-         * 128k runs at 3.5469 MHz
+    pub fn new(cpu_freq: u32, sample_rate: u32) -> Speaker {
+        /* Every T-state adds `sample_rate` units of time, and every sample takes `cpu_freq`
+         * units. There is a sample every cpu_freq / sample_rate T-states, that is not an integer.
          */
-        Speaker {
+        let mut speaker = Speaker {
             audio: vec![],
             audio_time: 0,
-            audio_accum: 0,
-            t_per_sample,
-        }
+            cpu_freq: u64::from(cpu_freq),
+            sample_rate: u64::from(sample_rate),
+            filter: SpeakerFilter::default(),
+            kernel: Vec::new(),
+            level: [0; CHANNELS],
+            deltas: [[0.0; CHANNELS]; BUF_LEN],
+            pos: 0,
+            sum: [0.0; CHANNELS],
+            dc_r: 0.0,
+            dc_x: [0.0; CHANNELS],
+            dc_y: [0.0; CHANNELS],
+        };
+        speaker.set_filter(SpeakerFilter::default());
+        speaker
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate as u32
+    }
+    pub fn filter(&self) -> SpeakerFilter {
+        self.filter
+    }
+    pub fn set_filter(&mut self, filter: SpeakerFilter) {
+        self.filter = filter;
+        let rate = self.sample_rate as f32;
+        //cutoff as a fraction of the sample rate
+        let fc = filter.low_pass.clamp(1.0, 0.4 * rate) / rate;
+        let half = (STEP_LEN / 2) as f32;
+        self.kernel = (0..=STEP_PHASES)
+            .map(|phase| {
+                let frac = phase as f32 / STEP_PHASES as f32;
+                let mut k = [0.0; STEP_LEN];
+                for (i, k) in k.iter_mut().enumerate() {
+                    //distance to the step, that is between the samples half-1 and half
+                    let x = i as f32 - (half - 1.0) - frac;
+                    let sinc = if x == 0.0 {
+                        2.0 * fc
+                    } else {
+                        (2.0 * PI * fc * x).sin() / (PI * x)
+                    };
+                    //Blackman window
+                    let u = (x + half) / (2.0 * half);
+                    let w = 0.42 - 0.5 * (2.0 * PI * u).cos() + 0.08 * (4.0 * PI * u).cos();
+                    *k = sinc * w.max(0.0);
+                }
+                //a step must be of the exact height
+                let total: f32 = k.iter().sum();
+                k.iter_mut().for_each(|k| *k /= total);
+                k
+            })
+            .collect();
+        self.dc_r = (1.0 - 2.0 * PI * filter.high_pass / rate).clamp(0.0, 1.0);
     }
     pub fn clear(&mut self) {
         self.audio.clear();
     }
-    pub fn push_sample(&mut self, sample: u32, t: u32) {
-        self.audio_time += t;
-        self.audio_accum += t * sample;
-        while self.audio_time >= self.t_per_sample {
-            //remove the excess samples
-            self.audio_time -= self.t_per_sample;
-            let audio_excess = self.audio_time * sample;
-            self.push_audio_accum(self.audio_accum - audio_excess);
-            self.audio_accum = audio_excess;
+    //Adds `t` T-states of the left and right levels `sample`
+    pub fn push_sample(&mut self, sample: [u32; CHANNELS], t: u32) {
+        for (ch, s) in sample.into_iter().enumerate() {
+            if s != self.level[ch] {
+                self.add_step(ch, s);
+            }
+        }
+        self.audio_time += u64::from(t) * self.sample_rate;
+        while self.audio_time >= self.cpu_freq {
+            self.audio_time -= self.cpu_freq;
+            self.push_audio();
         }
     }
     pub fn complete_frame(
         &mut self,
         full_time: u32,
-        mut sample_fn: impl FnMut() -> u32,
+        mut sample_fn: impl FnMut() -> [u32; CHANNELS],
     ) -> &mut [f32] {
-        while self.audio.len() < (full_time / self.t_per_sample) as usize {
+        let samples = u64::from(full_time) * self.sample_rate / self.cpu_freq;
+        while ((self.audio.len() / CHANNELS) as u64) < samples {
             let s = sample_fn();
-            self.push_sample(s, self.t_per_sample - self.audio_time);
+            //T-states until the next sample, rounded up
+            let t = (self.cpu_freq - self.audio_time).div_ceil(self.sample_rate);
+            self.push_sample(s, t as u32);
         }
         &mut self.audio
     }
 
-    fn push_audio_accum(&mut self, sample: u32) {
-        let v = sample as f32 / (65536 * self.t_per_sample) as f32 - 0.1;
-        self.audio.push(v);
+    //The level changes now, somewhere between the previous output sample and the next one
+    fn add_step(&mut self, ch: usize, sample: u32) {
+        let delta = (sample as f32 - self.level[ch] as f32) / 65536.0;
+        self.level[ch] = sample;
+        let phase = (self.audio_time * STEP_PHASES as u64 + self.cpu_freq / 2) / self.cpu_freq;
+        let kernel = &self.kernel[phase as usize];
+        for (i, k) in kernel.iter().enumerate() {
+            self.deltas[(self.pos + i) % BUF_LEN][ch] += delta * k;
+        }
+    }
+    fn push_audio(&mut self) {
+        let deltas = std::mem::take(&mut self.deltas[self.pos]);
+        self.pos = (self.pos + 1) % BUF_LEN;
+        for (ch, delta) in deltas.into_iter().enumerate() {
+            self.sum[ch] += delta;
+            //high-pass: y[n] = x[n] - x[n-1] + R * y[n-1]
+            let y = self.sum[ch] - self.dc_x[ch] + self.dc_r * self.dc_y[ch];
+            self.dc_x[ch] = self.sum[ch];
+            self.dc_y[ch] = y;
+            self.audio.push(y);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_rates() {
+        for rate in [22050, 44100, 48000, 96000] {
+            // 50 frames of 69888 T-states in instructions of 10 T-states, plus the remainder
+            let mut speaker = Speaker::new(3_500_000, rate);
+            let mut samples = 0;
+            for _ in 0..50 {
+                for _ in 0..6988 {
+                    speaker.push_sample([0x2000, 0x1000], 10);
+                }
+                speaker.push_sample([0x2000, 0x1000], 8);
+                samples += (speaker.audio.len() / 2) as u64;
+                speaker.clear();
+            }
+            // No rounding error accumulates in about 1 second of audio
+            let expected = 50 * 69888 * u64::from(rate) / 3_500_000;
+            assert!(samples.abs_diff(expected) <= 1, "{rate}: {samples}");
+            // The DC level is removed
+            assert!(speaker.sum[0] > 0.1 && speaker.dc_y[0].abs() < 1e-3);
+            assert!(speaker.sum[1] > 0.05 && speaker.dc_y[1].abs() < 1e-3);
+        }
+    }
+
+    // RMS of the output of a square wave that toggles every `half` T-states
+    fn square_rms(half: u32) -> f32 {
+        let mut speaker = Speaker::new(3_500_000, 44100);
+        for i in 0..200_000 {
+            let v = if (i / half).is_multiple_of(2) {
+                0x2000
+            } else {
+                0
+            };
+            speaker.push_sample([v, 0], 1);
+        }
+        // Only the left channel
+        let audio: Vec<f32> = speaker.audio[2000..].iter().step_by(2).copied().collect();
+        (audio.iter().map(|v| v * v).sum::<f32>() / audio.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn aliasing() {
+        // A 1 kHz square wave passes, its RMS is half the amplitude of 0.125
+        let rms = square_rms(1750);
+        assert!((rms - 0.0625).abs() < 0.005, "{rms}");
+        // A 30 kHz one is well above the Nyquist frequency, it should not alias to 14 kHz
+        let rms = square_rms(58);
+        assert!(rms < 0.005, "{rms}");
     }
 }
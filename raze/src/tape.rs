@@ -705,7 +705,7 @@ fn new_tzx(r: &mut impl Read, model: Model) -> anyhow::Result<Vec<Block>> {
                     return Err(anyhow!("invalid TAP-stop48k block"));
                 }
                 log::debug!("stop tape if 48k");
-                if model == Model::Spec48k {
+                if !model.is_128k() {
                     let block = Block::stop_block();
                     parser.add_block(block);
                 }
@@ -0,0 +1,96 @@
+use crate::game::Model;
+
+// Frame geometry of a model, all values in T-states
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timing {
+    pub cpu_freq: u32,
+    //clock of the AY-3-8910, if any
+    pub psg_clock: u32,
+    //length of a frame, from one interrupt to the next one
+    pub frame: i32,
+    //length of a scanline, including the border and the retrace
+    pub line: i32,
+    //scanline of the first pixel row of the screen, counting from the interrupt
+    pub first_line: i32,
+    //T-state of every scanline where the first pixel is drawn
+    pub line_offset: i32,
+    //the ULA starts delaying the CPU a few T-states before the first pixel
+    pub contention_start: i32,
+    //delay of a contended access, depending on the T-state inside every group of 8 pixels
+    pub contention: [u32; 8],
+    //duration of the INT signal, an interrupt not accepted by then is lost
+    pub int_length: i32,
+}
+
+//Delays of the ULA in the 48K and 128K
+const ULA_CONTENTION: [u32; 8] = [6, 5, 4, 3, 2, 1, 0, 0];
+//Delays of the gate array of the +2A/+3
+const GATE_ARRAY_CONTENTION: [u32; 8] = [1, 0, 7, 6, 5, 4, 3, 2];
+
+static TIMING_48K: Timing = Timing {
+    cpu_freq: 3_500_000,
+    //the usual clock of the add-on interfaces
+    psg_clock: 1_773_400,
+    frame: 69888,
+    line: 224,
+    first_line: 64,
+    line_offset: 0,
+    contention_start: 14335,
+    contention: ULA_CONTENTION,
+    int_length: 32,
+};
+
+static TIMING_128K: Timing = Timing {
+    cpu_freq: 3_546_900,
+    //half the CPU clock
+    psg_clock: 1_773_450,
+    frame: 70908,
+    line: 228,
+    first_line: 63,
+    line_offset: 0,
+    contention_start: 14361,
+    contention: ULA_CONTENTION,
+    int_length: 36,
+};
+
+//The +2A/+3 have the frame of the 128K, but the gate array contends memory with its own pattern
+static TIMING_PLUS2A: Timing = Timing {
+    cpu_freq: 3_546_900,
+    psg_clock: 1_773_450,
+    frame: 70908,
+    line: 228,
+    first_line: 63,
+    line_offset: 0,
+    contention_start: 14365,
+    contention: GATE_ARRAY_CONTENTION,
+    int_length: 32,
+};
+
+//The Pentagon has no contention, contention_start is just when the ULA reads the screen. Its
+//interrupt is not at the start of a scanline.
+static TIMING_PENTAGON: Timing = Timing {
+    cpu_freq: 3_500_000,
+    psg_clock: 1_750_000,
+    frame: 71680,
+    line: 224,
+    first_line: 80,
+    line_offset: 68,
+    contention_start: 17987,
+    contention: [0; 8],
+    int_length: 32,
+};
+
+impl Timing {
+    pub fn for_model(model: Model) -> Timing {
+        match model {
+            Model::Spec16k | Model::Spec48k | Model::Tc2048 => TIMING_48K,
+            Model::Spec128k | Model::Plus2 => TIMING_128K,
+            Model::Plus2A | Model::Plus3 => TIMING_PLUS2A,
+            Model::Pentagon => TIMING_PENTAGON,
+        }
+    }
+    //Scanlines after the screen until the next interrupt
+    pub fn bottom_lines(&self) -> i32 {
+        self.frame / self.line - self.first_line - 192
+    }
+}
@@ -0,0 +1,72 @@
+//Emulation of the ULAplus, a 64 color palette selected by the attributes
+
+pub struct UlaPlus {
+    //register selected with port 0xbf3b: bits 7-6 are the group, bits 5-0 the palette entry
+    reg: u8,
+    //4 CLUTs of 8 inks and 8 papers, each color is GRB332
+    palette: [u8; 64],
+    //if set the attributes index the palette instead of the classic colors
+    palette_mode: bool,
+    //palette or mode changed since the last call to take_changed()
+    changed: bool,
+}
+
+impl UlaPlus {
+    pub fn new() -> UlaPlus {
+        UlaPlus {
+            reg: 0,
+            palette: [0; 64],
+            palette_mode: false,
+            changed: true,
+        }
+    }
+    pub fn select_reg(&mut self, reg: u8) {
+        self.reg = reg;
+    }
+    pub fn read_data(&self) -> u8 {
+        match self.reg >> 6 {
+            0 => self.palette[usize::from(self.reg & 0x3f)],
+            1 => u8::from(self.palette_mode),
+            _ => 0xff,
+        }
+    }
+    pub fn write_data(&mut self, x: u8) {
+        match self.reg >> 6 {
+            0 => self.palette[usize::from(self.reg & 0x3f)] = x,
+            1 => self.palette_mode = x & 1 != 0,
+            _ => return,
+        }
+        self.changed = true;
+    }
+    pub fn palette_mode(&self) -> bool {
+        self.palette_mode
+    }
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+    //RGB value of entry `i` of the palette
+    pub fn rgb(&self, i: usize) -> (u8, u8, u8) {
+        let c = self.palette[i];
+        //3 bits to 8 bits
+        let x8 = |v: u8| (v << 5) | (v << 2) | (v >> 1);
+        let g = c >> 5;
+        let r = (c >> 2) & 7;
+        //blue has only 2 bits, the missing one is the OR of both
+        let b = c & 3;
+        let b = (b << 1) | u8::from(b != 0);
+        (x8(r), x8(g), x8(b))
+    }
+}
+
+//Index in the palette of the ink and paper of an attribute: bits 7-6 select the CLUT
+pub fn clut_index(attr: u8) -> (usize, usize) {
+    let clut = usize::from(attr >> 6) * 16;
+    let ink = clut + usize::from(attr & 7);
+    let paper = clut + 8 + usize::from((attr >> 3) & 7);
+    (ink, paper)
+}
+
+//Index in the palette of the border, it uses the papers of the first CLUT
+pub fn border_index(border: u8) -> usize {
+    8 + usize::from(border & 7)
+}
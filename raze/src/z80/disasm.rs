@@ -0,0 +1,356 @@
+// Z80 disassembler, including the undocumented opcodes.
+// It uses the classic decoding of the opcode as the bit fields xx_yyy_zzz, with yyy split as pp_q.
+
+static R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+static RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+static RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+static CC: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+static ALU: [&str; 8] = [
+    "ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP ",
+];
+static ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+static ACC: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+static IM: [&str; 8] = ["0", "0", "1", "2", "0", "0", "1", "2"];
+static BLOCK: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+// Decodes the instruction at `addr`, reading the memory with `peek`. It must not have side effects,
+// so do not use Bus::peek, that contends and triggers the watchpoints.
+// Returns the mnemonic and the length of the instruction in bytes.
+pub fn disasm(peek: impl Fn(u16) -> u8, addr: u16) -> (String, u16) {
+    let mut d = Decoder { peek, addr, len: 0 };
+    let text = d.decode();
+    (text, d.len)
+}
+
+// Same as disasm(), but the instruction is at the beginning of `code`, as if it were loaded at
+// `addr`. Bytes past the end of the slice read as 0.
+pub fn disasm_slice(code: &[u8], addr: u16) -> (String, u16) {
+    disasm(
+        |a| {
+            let offs = usize::from(a.wrapping_sub(addr));
+            code.get(offs).copied().unwrap_or(0)
+        },
+        addr,
+    )
+}
+
+struct Decoder<F> {
+    peek: F,
+    addr: u16,
+    len: u16,
+}
+
+impl<F: Fn(u16) -> u8> Decoder<F> {
+    fn look(&mut self) -> u8 {
+        (self.peek)(self.addr.wrapping_add(self.len))
+    }
+    fn next(&mut self) -> u8 {
+        let b = self.look();
+        self.len += 1;
+        b
+    }
+    fn n(&mut self) -> String {
+        format!("${:02X}", self.next())
+    }
+    fn nn(&mut self) -> String {
+        let lo = self.next();
+        let hi = self.next();
+        format!("${:04X}", u16::from_le_bytes([lo, hi]))
+    }
+    // Relative jumps are shown as the absolute target address
+    fn rel(&mut self) -> String {
+        let d = self.next() as i8;
+        let target = self
+            .addr
+            .wrapping_add(self.len)
+            .wrapping_add(i16::from(d) as u16);
+        format!("${target:04X}")
+    }
+    fn idx_disp(&mut self, ix: &str) -> String {
+        let d = self.next() as i8;
+        if d < 0 {
+            format!("({ix}-${:02X})", d.unsigned_abs())
+        } else {
+            format!("({ix}+${d:02X})")
+        }
+    }
+    // 8-bit register. With an index prefix H and L become IXH and IXL and (HL) becomes (IX+d).
+    fn r(&mut self, i: u8, ix: Option<&str>) -> String {
+        match (i, ix) {
+            (4, Some(ix)) => format!("{ix}H"),
+            (5, Some(ix)) => format!("{ix}L"),
+            (6, Some(ix)) => self.idx_disp(ix),
+            _ => String::from(R[usize::from(i)]),
+        }
+    }
+    fn rp(&self, i: u8, ix: Option<&str>) -> String {
+        match (i, ix) {
+            (2, Some(ix)) => String::from(ix),
+            _ => String::from(RP[usize::from(i)]),
+        }
+    }
+    fn rp2(&self, i: u8, ix: Option<&str>) -> String {
+        match (i, ix) {
+            (2, Some(ix)) => String::from(ix),
+            _ => String::from(RP2[usize::from(i)]),
+        }
+    }
+
+    fn decode(&mut self) -> String {
+        let op = self.next();
+        match op {
+            0xdd | 0xfd => {
+                let ix = if op == 0xdd { "IX" } else { "IY" };
+                match self.look() {
+                    // Another prefix follows, this one is ignored
+                    0xdd | 0xfd | 0xed => format!("DB ${op:02X}"),
+                    0xcb => {
+                        self.len += 1;
+                        self.decode_xycb(ix)
+                    }
+                    _ => {
+                        let op = self.next();
+                        self.decode_main(op, Some(ix))
+                    }
+                }
+            }
+            0xcb => {
+                let op = self.next();
+                self.decode_cb(op)
+            }
+            0xed => {
+                let op = self.next();
+                self.decode_ed(op)
+            }
+            _ => self.decode_main(op, None),
+        }
+    }
+
+    fn decode_main(&mut self, op: u8, ix: Option<&str>) -> String {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+        let hl = ix.unwrap_or("HL");
+        match (x, z) {
+            (0, 0) => match y {
+                0 => String::from("NOP"),
+                1 => String::from("EX AF,AF'"),
+                2 => format!("DJNZ {}", self.rel()),
+                3 => format!("JR {}", self.rel()),
+                _ => format!("JR {},{}", CC[usize::from(y - 4)], self.rel()),
+            },
+            (0, 1) => {
+                if q == 0 {
+                    format!("LD {},{}", self.rp(p, ix), self.nn())
+                } else {
+                    format!("ADD {hl},{}", self.rp(p, ix))
+                }
+            }
+            (0, 2) => match (q, p) {
+                (0, 0) => String::from("LD (BC),A"),
+                (0, 1) => String::from("LD (DE),A"),
+                (0, 2) => format!("LD ({}),{hl}", self.nn()),
+                (0, _) => format!("LD ({}),A", self.nn()),
+                (_, 0) => String::from("LD A,(BC)"),
+                (_, 1) => String::from("LD A,(DE)"),
+                (_, 2) => format!("LD {hl},({})", self.nn()),
+                (_, _) => format!("LD A,({})", self.nn()),
+            },
+            (0, 3) => {
+                let inst = if q == 0 { "INC" } else { "DEC" };
+                format!("{inst} {}", self.rp(p, ix))
+            }
+            (0, 4) => format!("INC {}", self.r(y, ix)),
+            (0, 5) => format!("DEC {}", self.r(y, ix)),
+            (0, 6) => {
+                let r = self.r(y, ix);
+                format!("LD {r},{}", self.n())
+            }
+            (0, _) => String::from(ACC[usize::from(y)]),
+            (1, _) => {
+                if y == 6 && z == 6 {
+                    String::from("HALT")
+                } else if y == 6 || z == 6 {
+                    // When (IX+d) is used, H and L are not replaced
+                    let dst = if y == 6 {
+                        self.r(y, ix)
+                    } else {
+                        self.r(y, None)
+                    };
+                    let src = if z == 6 {
+                        self.r(z, ix)
+                    } else {
+                        self.r(z, None)
+                    };
+                    format!("LD {dst},{src}")
+                } else {
+                    format!("LD {},{}", self.r(y, ix), self.r(z, ix))
+                }
+            }
+            (2, _) => format!("{}{}", ALU[usize::from(y)], self.r(z, ix)),
+            (_, 0) => format!("RET {}", CC[usize::from(y)]),
+            (_, 1) => match (q, p) {
+                (0, _) => format!("POP {}", self.rp2(p, ix)),
+                (_, 0) => String::from("RET"),
+                (_, 1) => String::from("EXX"),
+                (_, 2) => format!("JP ({hl})"),
+                (_, _) => format!("LD SP,{hl}"),
+            },
+            (_, 2) => format!("JP {},{}", CC[usize::from(y)], self.nn()),
+            (_, 3) => match y {
+                0 => format!("JP {}", self.nn()),
+                // 1 is the CB prefix, already handled
+                2 => format!("OUT ({}),A", self.n()),
+                3 => format!("IN A,({})", self.n()),
+                4 => format!("EX (SP),{hl}"),
+                5 => String::from("EX DE,HL"),
+                6 => String::from("DI"),
+                _ => String::from("EI"),
+            },
+            (_, 4) => format!("CALL {},{}", CC[usize::from(y)], self.nn()),
+            (_, 5) => {
+                if q == 0 {
+                    format!("PUSH {}", self.rp2(p, ix))
+                } else {
+                    // The other values are prefixes, already handled
+                    format!("CALL {}", self.nn())
+                }
+            }
+            (_, 6) => format!("{}{}", ALU[usize::from(y)], self.n()),
+            (_, _) => format!("RST ${:02X}", y * 8),
+        }
+    }
+
+    fn decode_cb(&mut self, op: u8) -> String {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let r = R[usize::from(z)];
+        match x {
+            0 => format!("{} {r}", ROT[usize::from(y)]),
+            1 => format!("BIT {y},{r}"),
+            2 => format!("RES {y},{r}"),
+            _ => format!("SET {y},{r}"),
+        }
+    }
+
+    // DDCB and FDCB: the displacement comes before the opcode
+    fn decode_xycb(&mut self, ix: &str) -> String {
+        let addr = self.idx_disp(ix);
+        let op = self.next();
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        // Undocumented: other than BIT, the result is also copied to a register
+        let copy = if z == 6 {
+            String::new()
+        } else {
+            format!(",{}", R[usize::from(z)])
+        };
+        match x {
+            0 => format!("{} {addr}{copy}", ROT[usize::from(y)]),
+            1 => format!("BIT {y},{addr}"),
+            2 => format!("RES {y},{addr}{copy}"),
+            _ => format!("SET {y},{addr}{copy}"),
+        }
+    }
+
+    fn decode_ed(&mut self, op: u8) -> String {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+        match (x, z) {
+            (1, 0) => {
+                if y == 6 {
+                    String::from("IN (C)")
+                } else {
+                    format!("IN {},(C)", R[usize::from(y)])
+                }
+            }
+            (1, 1) => {
+                if y == 6 {
+                    String::from("OUT (C),0")
+                } else {
+                    format!("OUT (C),{}", R[usize::from(y)])
+                }
+            }
+            (1, 2) => {
+                let inst = if q == 0 { "SBC" } else { "ADC" };
+                format!("{inst} HL,{}", RP[usize::from(p)])
+            }
+            (1, 3) => {
+                if q == 0 {
+                    format!("LD ({}),{}", self.nn(), RP[usize::from(p)])
+                } else {
+                    format!("LD {},({})", RP[usize::from(p)], self.nn())
+                }
+            }
+            (1, 4) => String::from("NEG"),
+            (1, 5) => String::from(if y == 1 { "RETI" } else { "RETN" }),
+            (1, 6) => format!("IM {}", IM[usize::from(y)]),
+            (1, 7) if y < 6 => {
+                String::from(["LD I,A", "LD R,A", "LD A,I", "LD A,R", "RRD", "RLD"][usize::from(y)])
+            }
+            (2, 0..=3) if y >= 4 => String::from(BLOCK[usize::from(y - 4)][usize::from(z)]),
+            // Everything else is a NOP of 2 bytes
+            _ => format!("DB $ED,${op:02X}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(code: &[u8], text: &str) {
+        assert_eq!(
+            disasm_slice(code, 0x8000),
+            (String::from(text), code.len() as u16)
+        );
+    }
+
+    #[test]
+    fn main_opcodes() {
+        check(&[0x00], "NOP");
+        check(&[0x01, 0x34, 0x12], "LD BC,$1234");
+        check(&[0x18, 0xfe], "JR $8000");
+        check(&[0x20, 0x05], "JR NZ,$8007");
+        check(&[0x36, 0x2a], "LD (HL),$2A");
+        check(&[0x7e], "LD A,(HL)");
+        check(&[0x76], "HALT");
+        check(&[0xfe, 0x10], "CP $10");
+        check(&[0xcd, 0x00, 0x40], "CALL $4000");
+        check(&[0xff], "RST $38");
+    }
+
+    #[test]
+    fn prefixed_opcodes() {
+        check(&[0xcb, 0x46], "BIT 0,(HL)");
+        check(&[0xcb, 0x37], "SLL A");
+        check(&[0xed, 0xb0], "LDIR");
+        check(&[0xed, 0x70], "IN (C)");
+        check(&[0xed, 0x4b, 0x00, 0x5c], "LD BC,($5C00)");
+        check(&[0xed, 0x00], "DB $ED,$00");
+        check(&[0xdd, 0x21, 0x00, 0x60], "LD IX,$6000");
+        check(&[0xdd, 0x36, 0xfe, 0x01], "LD (IX-$02),$01");
+        check(&[0xfd, 0x66, 0x03], "LD H,(IY+$03)");
+        check(&[0xdd, 0x65], "LD IXH,IXL");
+        check(&[0xdd, 0xe9], "JP (IX)");
+        check(&[0xdd, 0xcb, 0x05, 0x46], "BIT 0,(IX+$05)");
+        check(&[0xfd, 0xcb, 0x05, 0xc0], "SET 0,(IY+$05),B");
+        // A prefix followed by another prefix is a 1 byte instruction
+        assert_eq!(
+            disasm_slice(&[0xdd, 0xfd, 0x00], 0x8000),
+            (String::from("DB $DD"), 1)
+        );
+    }
+}
@@ -2,12 +2,13 @@ use super::*;
 
 impl Z80 {
     pub(super) fn exec_cb(&mut self, prefix: XYPrefix, bus: &mut impl Bus) -> u32 {
-        // If using XY prefix, then 0xCB does not increment R
-        if prefix == XYPrefix::None {
-            self.inc_r(bus, FetchReason::Prefix);
+        // With the XY prefix, 0xCB does not increment R
+        if prefix != XYPrefix::None {
+            return self.exec_xycb(prefix, bus);
         }
-        let (addr, t) = self.hlx_addr(prefix, bus);
-        let c = self.fetch(bus);
+        self.inc_r(bus, FetchReason::Prefix);
+        let addr = self.hl.as_u16();
+        let c = self.fetch_opcode(bus);
         match c {
             0x00 => {
                 //RLC B
@@ -55,8 +56,9 @@ impl Z80 {
                 //RLC (HL)
                 let r = bus.peek(addr);
                 let r = self.rlc_flags(r);
+                bus.internal(addr, 1);
                 bus.poke(addr, r);
-                t + 18
+                15
             }
             0x07 => {
                 //RLC A
@@ -111,8 +113,9 @@ impl Z80 {
                 //RRC (HL)
                 let r = bus.peek(addr);
                 let r = self.rrc_flags(r);
+                bus.internal(addr, 1);
                 bus.poke(addr, r);
-                t + 18
+                15
             }
             0x0f => {
                 //RRC A
@@ -167,8 +170,9 @@ impl Z80 {
                 //RL (HL)
                 let r = bus.peek(addr);
                 let r = self.rl_flags(r);
+                bus.internal(addr, 1);
                 bus.poke(addr, r);
-                t + 18
+                15
             }
             0x17 => {
                 //RL A
@@ -223,8 +227,9 @@ impl Z80 {
                 //RR (HL)
                 let r = bus.peek(addr);
                 let r = self.rr_flags(r);
+                bus.internal(addr, 1);
                 bus.poke(addr, r);
-                t + 18
+                15
             }
             0x1f => {
                 //RR A
@@ -279,8 +284,9 @@ impl Z80 {
                 //SLA (HL)
                 let r = bus.peek(addr);
                 let r = self.sla_flags(r);
+                bus.internal(addr, 1);
                 bus.poke(addr, r);
-                t + 18
+                15
             }
             0x27 => {
                 //SLA A
@@ -335,8 +341,9 @@ impl Z80 {
                 //SRA (HL)
                 let r = bus.peek(addr);
                 let r = self.sra_flags(r);
+                bus.internal(addr, 1);
                 bus.poke(addr, r);
-                t + 18
+                15
             }
             0x2f => {
                 //SRA A
@@ -391,8 +398,9 @@ impl Z80 {
                 //SL1 (HL)
                 let r = bus.peek(addr);
                 let r = self.sl1_flags(r);
+                bus.internal(addr, 1);
                 bus.poke(addr, r);
-                t + 18
+                15
             }
             0x37 => {
                 //SL1 A
@@ -447,8 +455,9 @@ impl Z80 {
                 //SRL (HL)
                 let r = bus.peek(addr);
                 let r = self.srl_flags(r);
+                bus.internal(addr, 1);
                 bus.poke(addr, r);
-                t + 18
+                15
             }
             0x3f => {
                 //SRL A
@@ -460,385 +469,393 @@ impl Z80 {
             0x40 => {
                 //BIT 0,B
                 let r = self.b();
-                self.bit_flags(r, 1 << 0);
+                self.bit_flags(r, 1 << 0, r);
                 8
             }
             0x41 => {
                 //BIT 0,C
                 let r = self.c();
-                self.bit_flags(r, 1 << 0);
+                self.bit_flags(r, 1 << 0, r);
                 8
             }
             0x42 => {
                 //BIT 0,D
                 let r = self.d();
-                self.bit_flags(r, 1 << 0);
+                self.bit_flags(r, 1 << 0, r);
                 8
             }
             0x43 => {
                 //BIT 0,E
                 let r = self.e();
-                self.bit_flags(r, 1 << 0);
+                self.bit_flags(r, 1 << 0, r);
                 8
             }
             0x44 => {
                 //BIT 0,H
                 let r = self.hx(prefix);
-                self.bit_flags(r, 1 << 0);
+                self.bit_flags(r, 1 << 0, r);
                 8
             }
             0x45 => {
                 //BIT 0,L
                 let r = self.lx(prefix);
-                self.bit_flags(r, 1 << 0);
+                self.bit_flags(r, 1 << 0, r);
                 8
             }
             0x46 => {
                 //BIT 0,(HL)
                 let r = bus.peek(addr);
-                self.bit_flags(r, 1 << 0);
-                t + 15
+                self.bit_flags(r, 1 << 0, self.wz.hi());
+                bus.internal(addr, 1);
+                12
             }
             0x47 => {
                 //BIT 0,A
                 let r = self.a();
-                self.bit_flags(r, 1 << 0);
+                self.bit_flags(r, 1 << 0, r);
                 8
             }
             0x48 => {
                 //BIT 1,B
                 let r = self.b();
-                self.bit_flags(r, 1 << 1);
+                self.bit_flags(r, 1 << 1, r);
                 8
             }
             0x49 => {
                 //BIT 1,C
                 let r = self.c();
-                self.bit_flags(r, 1 << 1);
+                self.bit_flags(r, 1 << 1, r);
                 8
             }
             0x4a => {
                 //BIT 1,D
                 let r = self.d();
-                self.bit_flags(r, 1 << 1);
+                self.bit_flags(r, 1 << 1, r);
                 8
             }
             0x4b => {
                 //BIT 1,E
                 let r = self.e();
-                self.bit_flags(r, 1 << 1);
+                self.bit_flags(r, 1 << 1, r);
                 8
             }
             0x4c => {
                 //BIT 1,H
                 let r = self.hx(prefix);
-                self.bit_flags(r, 1 << 1);
+                self.bit_flags(r, 1 << 1, r);
                 8
             }
             0x4d => {
                 //BIT 1,L
                 let r = self.lx(prefix);
-                self.bit_flags(r, 1 << 1);
+                self.bit_flags(r, 1 << 1, r);
                 8
             }
             0x4e => {
                 //BIT 1,(HL)
                 let r = bus.peek(addr);
-                self.bit_flags(r, 1 << 1);
-                t + 15
+                self.bit_flags(r, 1 << 1, self.wz.hi());
+                bus.internal(addr, 1);
+                12
             }
             0x4f => {
                 //BIT 1,A
                 let r = self.a();
-                self.bit_flags(r, 1 << 1);
+                self.bit_flags(r, 1 << 1, r);
                 8
             }
             0x50 => {
                 //BIT 2,B
                 let r = self.b();
-                self.bit_flags(r, 1 << 2);
+                self.bit_flags(r, 1 << 2, r);
                 8
             }
             0x51 => {
                 //BIT 2,C
                 let r = self.c();
-                self.bit_flags(r, 1 << 2);
+                self.bit_flags(r, 1 << 2, r);
                 8
             }
             0x52 => {
                 //BIT 2,D
                 let r = self.d();
-                self.bit_flags(r, 1 << 2);
+                self.bit_flags(r, 1 << 2, r);
                 8
             }
             0x53 => {
                 //BIT 2,E
                 let r = self.e();
-                self.bit_flags(r, 1 << 2);
+                self.bit_flags(r, 1 << 2, r);
                 8
             }
             0x54 => {
                 //BIT 2,H
                 let r = self.hx(prefix);
-                self.bit_flags(r, 1 << 2);
+                self.bit_flags(r, 1 << 2, r);
                 8
             }
             0x55 => {
                 //BIT 2,L
                 let r = self.lx(prefix);
-                self.bit_flags(r, 1 << 2);
+                self.bit_flags(r, 1 << 2, r);
                 8
             }
             0x56 => {
                 //BIT 2,(HL)
                 let r = bus.peek(addr);
-                self.bit_flags(r, 1 << 2);
-                t + 15
+                self.bit_flags(r, 1 << 2, self.wz.hi());
+                bus.internal(addr, 1);
+                12
             }
             0x57 => {
                 //BIT 2,A
                 let r = self.a();
-                self.bit_flags(r, 1 << 2);
+                self.bit_flags(r, 1 << 2, r);
                 8
             }
             0x58 => {
                 //BIT 3,B
                 let r = self.b();
-                self.bit_flags(r, 1 << 3);
+                self.bit_flags(r, 1 << 3, r);
                 8
             }
             0x59 => {
                 //BIT 3,C
                 let r = self.c();
-                self.bit_flags(r, 1 << 3);
+                self.bit_flags(r, 1 << 3, r);
                 8
             }
             0x5a => {
                 //BIT 3,D
                 let r = self.d();
-                self.bit_flags(r, 1 << 3);
+                self.bit_flags(r, 1 << 3, r);
                 8
             }
             0x5b => {
                 //BIT 3,E
                 let r = self.e();
-                self.bit_flags(r, 1 << 3);
+                self.bit_flags(r, 1 << 3, r);
                 8
             }
             0x5c => {
                 //BIT 3,H
                 let r = self.hx(prefix);
-                self.bit_flags(r, 1 << 3);
+                self.bit_flags(r, 1 << 3, r);
                 8
             }
             0x5d => {
                 //BIT 3,L
                 let r = self.lx(prefix);
-                self.bit_flags(r, 1 << 3);
+                self.bit_flags(r, 1 << 3, r);
                 8
             }
             0x5e => {
                 //BIT 3,(HL)
                 let r = bus.peek(addr);
-                self.bit_flags(r, 1 << 3);
-                t + 15
+                self.bit_flags(r, 1 << 3, self.wz.hi());
+                bus.internal(addr, 1);
+                12
             }
             0x5f => {
                 //BIT 3,A
                 let r = self.a();
-                self.bit_flags(r, 1 << 3);
+                self.bit_flags(r, 1 << 3, r);
                 8
             }
             0x60 => {
                 //BIT 4,B
                 let r = self.b();
-                self.bit_flags(r, 1 << 4);
+                self.bit_flags(r, 1 << 4, r);
                 8
             }
             0x61 => {
                 //BIT 4,C
                 let r = self.c();
-                self.bit_flags(r, 1 << 4);
+                self.bit_flags(r, 1 << 4, r);
                 8
             }
             0x62 => {
                 //BIT 4,D
                 let r = self.d();
-                self.bit_flags(r, 1 << 4);
+                self.bit_flags(r, 1 << 4, r);
                 8
             }
             0x63 => {
                 //BIT 4,E
                 let r = self.e();
-                self.bit_flags(r, 1 << 4);
+                self.bit_flags(r, 1 << 4, r);
                 8
             }
             0x64 => {
                 //BIT 4,H
                 let r = self.hx(prefix);
-                self.bit_flags(r, 1 << 4);
+                self.bit_flags(r, 1 << 4, r);
                 8
             }
             0x65 => {
                 //BIT 4,L
                 let r = self.lx(prefix);
-                self.bit_flags(r, 1 << 4);
+                self.bit_flags(r, 1 << 4, r);
                 8
             }
             0x66 => {
                 //BIT 4,(HL)
                 let r = bus.peek(addr);
-                self.bit_flags(r, 1 << 4);
-                t + 15
+                self.bit_flags(r, 1 << 4, self.wz.hi());
+                bus.internal(addr, 1);
+                12
             }
             0x67 => {
                 //BIT 4,A
                 let r = self.a();
-                self.bit_flags(r, 1 << 4);
+                self.bit_flags(r, 1 << 4, r);
                 8
             }
             0x68 => {
                 //BIT 5,B
                 let r = self.b();
-                self.bit_flags(r, 1 << 5);
+                self.bit_flags(r, 1 << 5, r);
                 8
             }
             0x69 => {
                 //BIT 5,C
                 let r = self.c();
-                self.bit_flags(r, 1 << 5);
+                self.bit_flags(r, 1 << 5, r);
                 8
             }
             0x6a => {
                 //BIT 5,D
                 let r = self.d();
-                self.bit_flags(r, 1 << 5);
+                self.bit_flags(r, 1 << 5, r);
                 8
             }
             0x6b => {
                 //BIT 5,E
                 let r = self.e();
-                self.bit_flags(r, 1 << 5);
+                self.bit_flags(r, 1 << 5, r);
                 8
             }
             0x6c => {
                 //BIT 5,H
                 let r = self.hx(prefix);
-                self.bit_flags(r, 1 << 5);
+                self.bit_flags(r, 1 << 5, r);
                 8
             }
             0x6d => {
                 //BIT 5,L
                 let r = self.lx(prefix);
-                self.bit_flags(r, 1 << 5);
+                self.bit_flags(r, 1 << 5, r);
                 8
             }
             0x6e => {
                 //BIT 5,(HL)
                 let r = bus.peek(addr);
-                self.bit_flags(r, 1 << 5);
-                t + 15
+                self.bit_flags(r, 1 << 5, self.wz.hi());
+                bus.internal(addr, 1);
+                12
             }
             0x6f => {
                 //BIT 5,A
                 let r = self.a();
-                self.bit_flags(r, 1 << 5);
+                self.bit_flags(r, 1 << 5, r);
                 8
             }
             0x70 => {
                 //BIT 6,B
                 let r = self.b();
-                self.bit_flags(r, 1 << 6);
+                self.bit_flags(r, 1 << 6, r);
                 8
             }
             0x71 => {
                 //BIT 6,C
                 let r = self.c();
-                self.bit_flags(r, 1 << 6);
+                self.bit_flags(r, 1 << 6, r);
                 8
             }
             0x72 => {
                 //BIT 6,D
                 let r = self.d();
-                self.bit_flags(r, 1 << 6);
+                self.bit_flags(r, 1 << 6, r);
                 8
             }
             0x73 => {
                 //BIT 6,E
                 let r = self.e();
-                self.bit_flags(r, 1 << 6);
+                self.bit_flags(r, 1 << 6, r);
                 8
             }
             0x74 => {
                 //BIT 6,H
                 let r = self.hx(prefix);
-                self.bit_flags(r, 1 << 6);
+                self.bit_flags(r, 1 << 6, r);
                 8
             }
             0x75 => {
                 //BIT 6,L
                 let r = self.lx(prefix);
-                self.bit_flags(r, 1 << 6);
+                self.bit_flags(r, 1 << 6, r);
                 8
             }
             0x76 => {
                 //BIT 6,(HL)
                 let r = bus.peek(addr);
-                self.bit_flags(r, 1 << 6);
-                t + 15
+                self.bit_flags(r, 1 << 6, self.wz.hi());
+                bus.internal(addr, 1);
+                12
             }
             0x77 => {
                 //BIT 6,A
                 let r = self.a();
-                self.bit_flags(r, 1 << 6);
+                self.bit_flags(r, 1 << 6, r);
                 8
             }
             0x78 => {
                 //BIT 7,B
                 let r = self.b();
-                self.bit_flags(r, 1 << 7);
+                self.bit_flags(r, 1 << 7, r);
                 8
             }
             0x79 => {
                 //BIT 7,C
                 let r = self.c();
-                self.bit_flags(r, 1 << 7);
+                self.bit_flags(r, 1 << 7, r);
                 8
             }
             0x7a => {
                 //BIT 7,D
                 let r = self.d();
-                self.bit_flags(r, 1 << 7);
+                self.bit_flags(r, 1 << 7, r);
                 8
             }
             0x7b => {
                 //BIT 7,E
                 let r = self.e();
-                self.bit_flags(r, 1 << 7);
+                self.bit_flags(r, 1 << 7, r);
                 8
             }
             0x7c => {
                 //BIT 7,H
                 let r = self.hx(prefix);
-                self.bit_flags(r, 1 << 7);
+                self.bit_flags(r, 1 << 7, r);
                 8
             }
             0x7d => {
                 //BIT 7,L
                 let r = self.lx(prefix);
-                self.bit_flags(r, 1 << 7);
+                self.bit_flags(r, 1 << 7, r);
                 8
             }
             0x7e => {
                 //BIT 7,(HL)
                 let r = bus.peek(addr);
-                self.bit_flags(r, 1 << 7);
-                t + 15
+                self.bit_flags(r, 1 << 7, self.wz.hi());
+                bus.internal(addr, 1);
+                12
             }
             0x7f => {
                 //BIT 7,A
                 let r = self.a();
-                self.bit_flags(r, 1 << 7);
+                self.bit_flags(r, 1 << 7, r);
                 8
             }
             0x80 => {
@@ -887,8 +904,9 @@ impl Z80 {
                 //RES 0,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 0, false);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0x87 => {
                 //RES 0,A
@@ -943,8 +961,9 @@ impl Z80 {
                 //RES 1,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 1, false);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0x8f => {
                 //RES 1,A
@@ -999,8 +1018,9 @@ impl Z80 {
                 //RES 2,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 2, false);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0x97 => {
                 //RES 2,A
@@ -1055,8 +1075,9 @@ impl Z80 {
                 //RES 3,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 3, false);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0x9f => {
                 //RES 3,A
@@ -1111,8 +1132,9 @@ impl Z80 {
                 //RES 4,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 4, false);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xa7 => {
                 //RES 4,A
@@ -1167,8 +1189,9 @@ impl Z80 {
                 //RES 5,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 5, false);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xaf => {
                 //RES 5,A
@@ -1223,8 +1246,9 @@ impl Z80 {
                 //RES 6,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 6, false);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xb7 => {
                 //RES 6,A
@@ -1279,8 +1303,9 @@ impl Z80 {
                 //RES 7,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 7, false);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xbf => {
                 //RES 7,A
@@ -1335,8 +1360,9 @@ impl Z80 {
                 //SET 0,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 0, true);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xc7 => {
                 //SET 0,A
@@ -1391,8 +1417,9 @@ impl Z80 {
                 //SET 1,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 1, true);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xcf => {
                 //SET 1,A
@@ -1447,8 +1474,9 @@ impl Z80 {
                 //SET 2,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 2, true);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xd7 => {
                 //SET 2,A
@@ -1503,8 +1531,9 @@ impl Z80 {
                 //SET 3,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 3, true);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xdf => {
                 //SET 3,A
@@ -1559,8 +1588,9 @@ impl Z80 {
                 //SET 4,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 4, true);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xe7 => {
                 //SET 4,A
@@ -1615,8 +1645,9 @@ impl Z80 {
                 //SET 5,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 5, true);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xef => {
                 //SET 5,A
@@ -1671,8 +1702,9 @@ impl Z80 {
                 //SET 6,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 6, true);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xf7 => {
                 //SET 6,A
@@ -1727,8 +1759,9 @@ impl Z80 {
                 //SET 7,(HL)
                 let b = bus.peek(addr);
                 let b = set_flag8(b, 1 << 7, true);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
-                t + 18
+                15
             }
             0xff => {
                 //SET 7,A
@@ -1739,6 +1772,49 @@ impl Z80 {
             }
         }
     }
+    // DDCB and FDCB: the displacement comes before the opcode, that is not fetched in an M1 cycle.
+    // They always operate on (IX+d) but, undocumented, the result of all but BIT is also copied
+    // to the register of the opcode.
+    fn exec_xycb(&mut self, prefix: XYPrefix, bus: &mut impl Bus) -> u32 {
+        let d = self.fetch(bus);
+        let addr = self.hlx(prefix).as_u16().wrapping_add(extend_sign(d));
+        self.wz.set(addr);
+        let c = self.fetch(bus);
+        bus.internal(self.pc.as_u16().wrapping_sub(1), 2);
+        let b = bus.peek(addr);
+        bus.internal(addr, 1);
+        let n = (c >> 3) & 0x07;
+        let r = match c >> 6 {
+            0 => match n {
+                0 => self.rlc_flags(b),
+                1 => self.rrc_flags(b),
+                2 => self.rl_flags(b),
+                3 => self.rr_flags(b),
+                4 => self.sla_flags(b),
+                5 => self.sra_flags(b),
+                6 => self.sl1_flags(b),
+                _ => self.srl_flags(b),
+            },
+            1 => {
+                self.bit_flags(b, 1 << n, self.wz.hi());
+                return 16;
+            }
+            2 => set_flag8(b, 1 << n, false),
+            _ => set_flag8(b, 1 << n, true),
+        };
+        bus.poke(addr, r);
+        match c & 0x07 {
+            0 => self.set_b(r),
+            1 => self.set_c(r),
+            2 => self.set_d(r),
+            3 => self.set_e(r),
+            4 => self.set_h(r),
+            5 => self.set_l(r),
+            6 => {}
+            _ => self.set_a(r),
+        }
+        19
+    }
     fn rlc_flags(&mut self, b: u8) -> u8 {
         let f = self.f();
         let b7 = flag8(b, 0x80);
@@ -1831,12 +1907,15 @@ impl Z80 {
         self.set_f(f);
         b
     }
-    fn bit_flags(&mut self, b: u8, m: u8) {
+    // X and Y flags are not taken from the result, but from `xy`: the tested register or,
+    // when testing memory, the high byte of MEMPTR.
+    fn bit_flags(&mut self, b: u8, m: u8, xy: u8) {
         let r = b & m;
         let f = self.f();
         let f = set_flag8(f, FLAG_H, true);
         let f = set_flag8(f, FLAG_N, false);
         let f = set_flag_szp(f, r);
+        let f = set_flag_xy(f, xy);
         self.set_f(f);
     }
 }
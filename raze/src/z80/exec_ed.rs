@@ -6,13 +6,14 @@ impl Z80 {
         if prefix == XYPrefix::None {
             self.inc_r(bus, FetchReason::Prefix);
         }
-        let c = self.fetch(bus);
+        let c = self.fetch_opcode(bus);
         match c {
             0x40 => {
                 //IN B,(C)
                 let bc = self.bc.as_u16();
                 let mut f = self.f();
                 let b = bus.do_in(bc);
+                self.wz.set(bc.wrapping_add(1));
                 f = set_flag8(f, FLAG_N, false);
                 f = set_flag8(f, FLAG_H, false);
                 f = set_flag_szp(f, b);
@@ -24,6 +25,7 @@ impl Z80 {
                 //OUT (C),B
                 let bc = self.bc.as_u16();
                 bus.do_out(bc, self.b());
+                self.wz.set(bc.wrapping_add(1));
                 12
             }
             0x42 => {
@@ -32,12 +34,14 @@ impl Z80 {
                 let bc = self.bc.as_u16();
                 hl = self.sbc16_flags(hl, bc);
                 self.hl.set(hl);
+                bus.internal(self.ir(), 7);
                 15
             }
             0x43 => {
                 //LD (nn),BC
                 let addr = self.fetch_u16(bus);
                 bus.poke_u16(addr, self.bc.as_u16());
+                self.wz.set(addr.wrapping_add(1));
                 20
             }
             0x44 => {
@@ -51,6 +55,9 @@ impl Z80 {
                 //RETN
                 let pc = self.pop(bus);
                 self.pc.set(pc);
+                self.wz.set(pc);
+                // Restores the IFF1 saved by the NMI
+                self.iff1 = self.iff2;
                 14
             }
             0x46 => {
@@ -60,6 +67,7 @@ impl Z80 {
             }
             0x47 => {
                 //LD I,A
+                bus.internal(self.ir(), 1);
                 self.i = self.a();
                 9
             }
@@ -68,6 +76,7 @@ impl Z80 {
                 let bc = self.bc.as_u16();
                 let mut f = self.f();
                 let b = bus.do_in(bc);
+                self.wz.set(bc.wrapping_add(1));
                 f = set_flag8(f, FLAG_N, false);
                 f = set_flag8(f, FLAG_H, false);
                 f = set_flag_szp(f, b);
@@ -79,6 +88,7 @@ impl Z80 {
                 //OUT (C),C
                 let bc = self.bc.as_u16();
                 bus.do_out(bc, self.c());
+                self.wz.set(bc.wrapping_add(1));
                 12
             }
             0x4a => {
@@ -87,22 +97,28 @@ impl Z80 {
                 let bc = self.bc.as_u16();
                 hl = self.adc16_flags(hl, bc);
                 self.hl.set(hl);
+                bus.internal(self.ir(), 7);
                 15
             }
             0x4b => {
                 //LD BC,(nn)
                 let addr = self.fetch_u16(bus);
                 self.bc.set(bus.peek_u16(addr));
+                self.wz.set(addr.wrapping_add(1));
                 20
             }
             0x4d => {
                 //RETI
                 let pc = self.pop(bus);
                 self.pc.set(pc);
+                self.wz.set(pc);
+                // Undocumented, but RETI does the same as RETN
+                self.iff1 = self.iff2;
                 14
             }
             0x4f => {
                 //LD R,A
+                bus.internal(self.ir(), 1);
                 let a = self.a();
                 self.set_r(a);
                 9
@@ -112,6 +128,7 @@ impl Z80 {
                 let bc = self.bc.as_u16();
                 let mut f = self.f();
                 let b = bus.do_in(bc);
+                self.wz.set(bc.wrapping_add(1));
                 f = set_flag8(f, FLAG_N, false);
                 f = set_flag8(f, FLAG_H, false);
                 f = set_flag_szp(f, b);
@@ -123,6 +140,7 @@ impl Z80 {
                 //OUT (C),D
                 let bc = self.bc.as_u16();
                 bus.do_out(bc, self.d());
+                self.wz.set(bc.wrapping_add(1));
                 12
             }
             0x52 => {
@@ -131,12 +149,14 @@ impl Z80 {
                 let de = self.de.as_u16();
                 hl = self.sbc16_flags(hl, de);
                 self.hl.set(hl);
+                bus.internal(self.ir(), 7);
                 15
             }
             0x53 => {
                 //LD (nn),DE
                 let addr = self.fetch_u16(bus);
                 bus.poke_u16(addr, self.de.as_u16());
+                self.wz.set(addr.wrapping_add(1));
                 20
             }
             0x56 => {
@@ -146,11 +166,12 @@ impl Z80 {
             }
             0x57 => {
                 //LD A,I
+                bus.internal(self.ir(), 1);
                 let i = self.i;
                 let mut f = self.f();
                 f = set_flag8(f, FLAG_H, false);
                 f = set_flag8(f, FLAG_N, false);
-                f = set_flag8(f, FLAG_PV, self.iff1);
+                f = set_flag8(f, FLAG_PV, self.iff2);
                 f = set_flag_sz(f, i);
                 self.set_a(i);
                 self.set_f(f);
@@ -161,6 +182,7 @@ impl Z80 {
                 let bc = self.bc.as_u16();
                 let mut f = self.f();
                 let b = bus.do_in(bc);
+                self.wz.set(bc.wrapping_add(1));
                 f = set_flag8(f, FLAG_N, false);
                 f = set_flag8(f, FLAG_H, false);
                 f = set_flag_szp(f, b);
@@ -172,6 +194,7 @@ impl Z80 {
                 //OUT (C),E
                 let bc = self.bc.as_u16();
                 bus.do_out(bc, self.e());
+                self.wz.set(bc.wrapping_add(1));
                 12
             }
             0x5a => {
@@ -180,12 +203,14 @@ impl Z80 {
                 let de = self.de.as_u16();
                 hl = self.adc16_flags(hl, de);
                 self.hl.set(hl);
+                bus.internal(self.ir(), 7);
                 15
             }
             0x5b => {
                 //LD DE,(nn)
                 let addr = self.fetch_u16(bus);
                 self.de.set(bus.peek_u16(addr));
+                self.wz.set(addr.wrapping_add(1));
                 20
             }
             0x5e => {
@@ -195,11 +220,12 @@ impl Z80 {
             }
             0x5f => {
                 //LD A,R
+                bus.internal(self.ir(), 1);
                 let r = self.r();
                 let mut f = self.f();
                 f = set_flag8(f, FLAG_H, false);
                 f = set_flag8(f, FLAG_N, false);
-                f = set_flag8(f, FLAG_PV, self.iff1);
+                f = set_flag8(f, FLAG_PV, self.iff2);
                 f = set_flag_sz(f, r);
                 self.set_a(r);
                 self.set_f(f);
@@ -210,6 +236,7 @@ impl Z80 {
                 let bc = self.bc.as_u16();
                 let mut f = self.f();
                 let b = bus.do_in(bc);
+                self.wz.set(bc.wrapping_add(1));
                 f = set_flag8(f, FLAG_N, false);
                 f = set_flag8(f, FLAG_H, false);
                 f = set_flag_szp(f, b);
@@ -221,6 +248,7 @@ impl Z80 {
                 //OUT (C),H
                 let bc = self.bc.as_u16();
                 bus.do_out(bc, self.h());
+                self.wz.set(bc.wrapping_add(1));
                 12
             }
             0x62 => {
@@ -228,12 +256,14 @@ impl Z80 {
                 let mut hl = self.hl.as_u16();
                 hl = self.sbc16_flags(hl, hl);
                 self.hl.set(hl);
+                bus.internal(self.ir(), 7);
                 15
             }
             0x63 => {
                 //LD (nn),HL
                 let addr = self.fetch_u16(bus);
                 bus.poke_u16(addr, self.hl.as_u16());
+                self.wz.set(addr.wrapping_add(1));
                 20
             }
             0x67 => {
@@ -248,7 +278,9 @@ impl Z80 {
                 f = set_flag_szp(f, new_a);
                 self.set_a(new_a);
                 self.set_f(f);
+                bus.internal(self.hl, 4);
                 bus.poke(self.hl, new_x);
+                self.wz.set(self.hl.as_u16().wrapping_add(1));
                 18
             }
             0x68 => {
@@ -256,6 +288,7 @@ impl Z80 {
                 let bc = self.bc.as_u16();
                 let mut f = self.f();
                 let b = bus.do_in(bc);
+                self.wz.set(bc.wrapping_add(1));
                 f = set_flag8(f, FLAG_N, false);
                 f = set_flag8(f, FLAG_H, false);
                 f = set_flag_szp(f, b);
@@ -267,6 +300,7 @@ impl Z80 {
                 //OUT (C),L
                 let bc = self.bc.as_u16();
                 bus.do_out(bc, self.l());
+                self.wz.set(bc.wrapping_add(1));
                 12
             }
             0x6a => {
@@ -274,12 +308,14 @@ impl Z80 {
                 let mut hl = self.hl.as_u16();
                 hl = self.adc16_flags(hl, hl);
                 self.hl.set(hl);
+                bus.internal(self.ir(), 7);
                 15
             }
             0x6b => {
                 //LD HL,(nn)
                 let addr = self.fetch_u16(bus);
                 self.hl.set(bus.peek_u16(addr));
+                self.wz.set(addr.wrapping_add(1));
                 20
             }
             0x6f => {
@@ -294,13 +330,16 @@ impl Z80 {
                 f = set_flag_szp(f, new_a);
                 self.set_a(new_a);
                 self.set_f(f);
+                bus.internal(self.hl, 4);
                 bus.poke(self.hl, new_x);
+                self.wz.set(self.hl.as_u16().wrapping_add(1));
                 18
             }
             0x70 => {
                 //IN F,(C)
                 let bc = self.bc.as_u16();
                 let b = bus.do_in(bc);
+                self.wz.set(bc.wrapping_add(1));
                 self.set_f(b);
                 12
             }
@@ -308,6 +347,7 @@ impl Z80 {
                 //OUT (C),F
                 let bc = self.bc.as_u16();
                 bus.do_out(bc, self.f());
+                self.wz.set(bc.wrapping_add(1));
                 12
             }
             0x72 => {
@@ -316,12 +356,14 @@ impl Z80 {
                 let sp = self.sp.as_u16();
                 hl = self.sbc16_flags(hl, sp);
                 self.hl.set(hl);
+                bus.internal(self.ir(), 7);
                 15
             }
             0x73 => {
                 //LD (nn),SP
                 let addr = self.fetch_u16(bus);
                 bus.poke_u16(addr, self.sp.as_u16());
+                self.wz.set(addr.wrapping_add(1));
                 20
             }
             0x78 => {
@@ -329,6 +371,7 @@ impl Z80 {
                 let bc = self.bc.as_u16();
                 let mut f = self.f();
                 let b = bus.do_in(bc);
+                self.wz.set(bc.wrapping_add(1));
                 f = set_flag8(f, FLAG_N, false);
                 f = set_flag8(f, FLAG_H, false);
                 f = set_flag_szp(f, b);
@@ -340,6 +383,7 @@ impl Z80 {
                 //OUT (C),A
                 let bc = self.bc.as_u16();
                 bus.do_out(bc, self.a());
+                self.wz.set(bc.wrapping_add(1));
                 12
             }
             0x7a => {
@@ -348,12 +392,14 @@ impl Z80 {
                 let sp = self.sp.as_u16();
                 hl = self.adc16_flags(hl, sp);
                 self.hl.set(hl);
+                bus.internal(self.ir(), 7);
                 15
             }
             0x7b => {
                 //LD SP,(nn)
                 let addr = self.fetch_u16(bus);
                 self.sp.set(bus.peek_u16(addr));
+                self.wz.set(addr.wrapping_add(1));
                 20
             }
             0xa0 => {
@@ -400,7 +446,9 @@ impl Z80 {
                 //LDIR
                 self.ldi_ldd(Direction::Inc, bus);
                 if self.bc.as_u16() != 0 {
+                    bus.internal(self.de.as_u16().wrapping_sub(1), 5);
                     self.pc -= 2;
+                    self.wz.set(self.pc.as_u16().wrapping_add(1));
                     21
                 } else {
                     16
@@ -410,7 +458,9 @@ impl Z80 {
                 //CPIR
                 let r = self.cpi_cpd(Direction::Inc, bus);
                 if self.bc.as_u16() != 0 && r != 0 {
+                    bus.internal(self.hl.as_u16().wrapping_sub(1), 5);
                     self.pc -= 2;
+                    self.wz.set(self.pc.as_u16().wrapping_add(1));
                     21
                 } else {
                     16
@@ -420,6 +470,7 @@ impl Z80 {
                 //INIR
                 let b = self.ini_ind(Direction::Inc, bus);
                 if b != 0 {
+                    bus.internal(self.hl.as_u16().wrapping_sub(1), 5);
                     self.pc -= 2;
                     21
                 } else {
@@ -430,6 +481,7 @@ impl Z80 {
                 //OTIR
                 let b = self.outi_outd(Direction::Inc, bus);
                 if b != 0 {
+                    bus.internal(self.bc.as_u16(), 5);
                     self.pc -= 2;
                     21
                 } else {
@@ -440,7 +492,9 @@ impl Z80 {
                 //LDDR
                 self.ldi_ldd(Direction::Dec, bus);
                 if self.bc.as_u16() != 0 {
+                    bus.internal(self.de.as_u16().wrapping_add(1), 5);
                     self.pc -= 2;
+                    self.wz.set(self.pc.as_u16().wrapping_add(1));
                     21
                 } else {
                     16
@@ -450,7 +504,9 @@ impl Z80 {
                 //CPDR
                 let r = self.cpi_cpd(Direction::Dec, bus);
                 if self.bc.as_u16() != 0 && r != 0 {
+                    bus.internal(self.hl.as_u16().wrapping_add(1), 5);
                     self.pc -= 2;
+                    self.wz.set(self.pc.as_u16().wrapping_add(1));
                     21
                 } else {
                     16
@@ -460,6 +516,7 @@ impl Z80 {
                 //INDR
                 let b = self.ini_ind(Direction::Dec, bus);
                 if b != 0 {
+                    bus.internal(self.hl.as_u16().wrapping_add(1), 5);
                     self.pc -= 2;
                     21
                 } else {
@@ -470,6 +527,7 @@ impl Z80 {
                 //OTDR
                 let b = self.outi_outd(Direction::Dec, bus);
                 if b != 0 {
+                    bus.internal(self.bc.as_u16(), 5);
                     self.pc -= 2;
                     21
                 } else {
@@ -482,7 +540,7 @@ impl Z80 {
                     c,
                     self.pc.as_u16()
                 );
-                0
+                8
             }
         }
     }
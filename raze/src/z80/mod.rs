@@ -1,10 +1,12 @@
 use anyhow::anyhow;
 use std::mem;
 
+pub mod disasm;
 mod r16;
 
 use self::r16::R16;
 
+// Every access is a machine cycle: memory reads and writes take 3 T-states, I/O 4 T-states
 pub trait Bus {
     fn peek(&mut self, addr: impl Into<u16>) -> u8;
     fn poke(&mut self, addr: impl Into<u16>, value: u8);
@@ -24,7 +26,28 @@ pub trait Bus {
         let addr = addr.wrapping_add(1);
         self.poke(addr, (data >> 8) as u8);
     }
+    // Reads a byte of the instruction stream: displacements and immediate operands
+    fn peek_code(&mut self, addr: impl Into<u16>) -> u8 {
+        self.peek(addr)
+    }
+    // Fetches an opcode, in an M1 cycle of 4 T-states instead of the 3 of a memory read
+    fn fetch_opcode(&mut self, addr: impl Into<u16>) -> u8 {
+        self.peek_code(addr)
+    }
+    // The refresh address of the M1 that just finished, it is in the address bus in its last 2
+    // T-states
+    fn refresh(&mut self, _ir: u16) {}
+    // `t` T-states in which the CPU is busy without accessing memory, while keeping `addr` in the
+    // address bus
+    fn internal(&mut self, _addr: impl Into<u16>, _t: u32) {}
+    // `t` T-states in which the address bus is not meaningful, such as an interrupt acknowledge
+    fn idle(&mut self, _t: u32) {}
     fn inc_fetch_count(&mut self, _reason: FetchReason) {}
+    // The value in the data bus during an interrupt acknowledge: the opcode to run in IM 0, or
+    // the low byte of the vector in IM 2. If no device drives it, it reads as 0xff.
+    fn int_data_bus(&mut self) -> u8 {
+        0xff
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -137,6 +160,13 @@ fn set_flag_szp(f: u8, r: u8) -> u8 {
     set_flag_sz(f, r)
 }
 
+#[inline]
+#[must_use]
+fn set_flag_xy(f: u8, r: u8) -> u8 {
+    const CF: u8 = FLAG_X | FLAG_Y;
+    (f & !CF) | (r & CF)
+}
+
 #[inline]
 #[must_use]
 fn extend_sign(x: u8) -> u16 {
@@ -144,7 +174,7 @@ fn extend_sign(x: u8) -> u16 {
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
-enum InterruptMode {
+pub enum InterruptMode {
     IM0,
     IM1,
     IM2,
@@ -161,6 +191,7 @@ enum XYPrefix {
 enum NextOp {
     Fetch,
     Interrupt,
+    Nmi,
     Halt,
 }
 
@@ -180,9 +211,40 @@ pub struct Z80 {
     i: u8,
     r_: u8, //bit 7 should not be used, use r7 instead, or better yet, r()
     r7: bool,
+    // MEMPTR, an internal register only visible through the X/Y flags of some instructions
+    wz: R16,
     iff1: bool,
+    iff2: bool,
     im: InterruptMode,
     next_op: NextOp,
+    // Level of the INT line, it is checked before every instruction
+    int_line: bool,
+    // Interrupts are not accepted just after an EI
+    after_ei: bool,
+}
+
+// A copy of the state of the CPU, to inspect or modify it from outside
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub pc: u16,
+    pub sp: u16,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub af_: u16,
+    pub bc_: u16,
+    pub de_: u16,
+    pub hl_: u16,
+    pub ix: u16,
+    pub iy: u16,
+    pub i: u8,
+    pub r: u8,
+    pub memptr: u16,
+    pub im: InterruptMode,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub halted: bool,
 }
 
 #[derive(Clone, Copy)]
@@ -226,14 +288,18 @@ impl Z80 {
             i: 0,
             r_: 0,
             r7: false,
+            wz: R16::default(),
             iff1: false,
+            iff2: false,
             im: InterruptMode::IM0,
             next_op: NextOp::Fetch,
+            int_line: false,
+            after_ei: false,
         }
     }
     pub fn _dump_regs(&self) {
         log::debug!(
-            "PC {:04x}; AF {:04x}; BC {:04x}; DE {:04x}; HL {:04x}; IR {:02x}{:02x}; INT {}-{}",
+            "PC {:04x}; AF {:04x}; BC {:04x}; DE {:04x}; HL {:04x}; IR {:02x}{:02x}; INT {}-{}/{}",
             self.pc.as_u16(),
             self.af.as_u16() & 0xffd7,
             self.bc.as_u16(),
@@ -246,9 +312,59 @@ impl Z80 {
                 InterruptMode::IM1 => 1,
                 InterruptMode::IM2 => 2,
             },
-            if self.iff1 { 1 } else { 0 }
+            if self.iff1 { 1 } else { 0 },
+            if self.iff2 { 1 } else { 0 }
         );
     }
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc.as_u16(),
+            sp: self.sp.as_u16(),
+            af: self.af.as_u16(),
+            bc: self.bc.as_u16(),
+            de: self.de.as_u16(),
+            hl: self.hl.as_u16(),
+            af_: self.af_.as_u16(),
+            bc_: self.bc_.as_u16(),
+            de_: self.de_.as_u16(),
+            hl_: self.hl_.as_u16(),
+            ix: self.ix.as_u16(),
+            iy: self.iy.as_u16(),
+            i: self.i,
+            r: self.r(),
+            memptr: self.wz.as_u16(),
+            im: self.im,
+            iff1: self.iff1,
+            iff2: self.iff2,
+            halted: self.next_op == NextOp::Halt,
+        }
+    }
+    // A pending interrupt is kept, unless the CPU is halted or unhalted here
+    pub fn set_registers(&mut self, regs: &Registers) {
+        self.pc.set(regs.pc);
+        self.sp.set(regs.sp);
+        self.af.set(regs.af);
+        self.bc.set(regs.bc);
+        self.de.set(regs.de);
+        self.hl.set(regs.hl);
+        self.af_.set(regs.af_);
+        self.bc_.set(regs.bc_);
+        self.de_.set(regs.de_);
+        self.hl_.set(regs.hl_);
+        self.ix.set(regs.ix);
+        self.iy.set(regs.iy);
+        self.i = regs.i;
+        self.set_r(regs.r);
+        self.wz.set(regs.memptr);
+        self.im = regs.im;
+        self.iff1 = regs.iff1;
+        self.iff2 = regs.iff2;
+        if regs.halted {
+            self.next_op = NextOp::Halt;
+        } else if self.next_op == NextOp::Halt {
+            self.next_op = NextOp::Fetch;
+        }
+    }
     // 30 bytes are required for the snapshot, here we always store a V1, and
     // if other version is required it will be transformed later, because the V2/V3
     // information is not related to the CPU.
@@ -281,7 +397,7 @@ impl Z80 {
         data[25] = self.ix.lo();
         data[26] = self.ix.hi();
         data[27] = if self.iff1 { 1 } else { 0 };
-        data[28] = data[27]; //iff2
+        data[28] = if self.iff2 { 1 } else { 0 };
         data[29] = match self.im {
             InterruptMode::IM0 => 0,
             InterruptMode::IM1 => 1,
@@ -309,7 +425,7 @@ impl Z80 {
         let iy = R16::from_bytes(data[23], data[24]);
         let ix = R16::from_bytes(data[25], data[26]);
         let iff1 = data[27] != 0;
-        //let iff2 = data[28];
+        let iff2 = data[28] != 0;
         let im = match data[29] & 0x03 {
             1 => InterruptMode::IM1,
             2 => InterruptMode::IM2,
@@ -348,24 +464,70 @@ impl Z80 {
             i,
             r_,
             r7,
+            wz: R16::default(),
             iff1,
+            iff2,
             im,
             next_op: NextOp::Fetch,
+            int_line: false,
+            after_ei: false,
         };
         Ok((z80, version))
     }
-    // Signals the CPU to run an interrupt on next fetch
-    pub fn interrupt(&mut self) {
-        if !self.iff1 {
-            return;
+    // The 27 bytes header of a SNA snapshot. The PC is not there: a 48K snapshot has it pushed
+    // in the stack, a 128K one stores it after the first 48K of RAM.
+    pub fn load_sna(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 27 {
+            return Err(anyhow!("SNA snaphot too short"));
         }
-        self.next_op = NextOp::Interrupt;
+        let w = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let iff = data[19] & 0x04 != 0;
+        let mut z80 = Z80::new();
+        z80.set_registers(&Registers {
+            pc: 0,
+            sp: w(23),
+            af: w(21),
+            bc: w(13),
+            de: w(11),
+            hl: w(9),
+            af_: w(7),
+            bc_: w(5),
+            de_: w(3),
+            hl_: w(1),
+            ix: w(17),
+            iy: w(15),
+            i: data[0],
+            r: data[20],
+            memptr: 0,
+            im: match data[25] & 0x03 {
+                1 => InterruptMode::IM1,
+                2 => InterruptMode::IM2,
+                _ => InterruptMode::IM0,
+            },
+            iff1: iff,
+            iff2: iff,
+            halted: false,
+        });
+        Ok(z80)
+    }
+    // Sets the level of the INT line. While it is active, and if interrupts are enabled, the
+    // CPU runs an interrupt instead of the next instruction.
+    pub fn set_int(&mut self, active: bool) {
+        self.int_line = active;
+    }
+    // Signals the CPU to run a non-maskable interrupt on next fetch, it cannot be disabled
+    pub fn nmi(&mut self) {
+        self.next_op = NextOp::Nmi;
     }
     // The value of the R register, as a full 8-bit value
     #[inline]
     fn r(&self) -> u8 {
         (self.r_ & 0x7f) | if self.r7 { 0x80 } else { 0x00 }
     }
+    // IR is in the address bus during the refresh, and it stays there in many internal cycles
+    fn ir(&self) -> u16 {
+        (u16::from(self.i) << 8) | u16::from(self.r())
+    }
     // Increments the R register as result of a memory cycle
     #[inline]
     fn inc_r(&mut self, bus: &mut impl Bus, reason: FetchReason) {
@@ -460,17 +622,24 @@ impl Z80 {
         self.hlx_mut(prefix).set_lo(f);
     }
 
+    // Fetches an opcode from address PC, in an M1 cycle, and increments PC
+    fn fetch_opcode(&mut self, bus: &mut impl Bus) -> u8 {
+        let c = bus.fetch_opcode(self.pc);
+        bus.refresh(self.ir());
+        self.pc += 1;
+        c
+    }
     // Fetches an 8-bit value from address PC and increments PC
     fn fetch(&mut self, bus: &mut impl Bus) -> u8 {
-        let c = bus.peek(self.pc);
+        let c = bus.peek_code(self.pc);
         self.pc += 1;
         c
     }
     // Fetches a 16-bit value from address PC and increments PC
     fn fetch_u16(&mut self, bus: &mut impl Bus) -> u16 {
-        let l = u16::from(bus.peek(self.pc));
+        let l = u16::from(bus.peek_code(self.pc));
         self.pc += 1;
-        let h = u16::from(bus.peek(self.pc));
+        let h = u16::from(bus.peek_code(self.pc));
         self.pc += 1;
         (h << 8) | l
     }
@@ -506,19 +675,31 @@ impl Z80 {
     }
     // Gets either HL, IX+n or IY+n. If needed n is fetched from PC.
     // Returns the (address, extra_T_states).
+    // The indexed forms also store the address into MEMPTR.
     fn hlx_addr(&mut self, prefix: XYPrefix, bus: &mut impl Bus) -> (u16, u32) {
         match prefix {
             XYPrefix::None => (self.hl.as_u16(), 0),
             XYPrefix::IX => {
                 let d = self.fetch(bus);
-                (self.ix.as_u16().wrapping_add(extend_sign(d)), 8)
+                bus.internal(self.pc.as_u16().wrapping_sub(1), 5);
+                let addr = self.ix.as_u16().wrapping_add(extend_sign(d));
+                self.wz.set(addr);
+                (addr, 8)
             }
             XYPrefix::IY => {
                 let d = self.fetch(bus);
-                (self.iy.as_u16().wrapping_add(extend_sign(d)), 8)
+                bus.internal(self.pc.as_u16().wrapping_sub(1), 5);
+                let addr = self.iy.as_u16().wrapping_add(extend_sign(d));
+                self.wz.set(addr);
+                (addr, 8)
             }
         }
     }
+    // Sets the X and Y flags from the A register, as SCF and CCF do
+    fn set_flag_xy_from_a(&mut self) {
+        let f = set_flag_xy(self.f(), self.a());
+        self.set_f(f);
+    }
     // Substracts two 8-bit values, maybe with carry
     fn sub_flags(&mut self, a: u8, b: u8, with_carry: bool) -> u8 {
         let mut r = a.wrapping_sub(b);
@@ -547,7 +728,9 @@ impl Z80 {
         f = set_flag8(f, FLAG_Z, r == 0);
         f = set_flag8(f, FLAG_S, flag16(r, 0x8000));
         f = set_flag8(f, FLAG_H, half_carry16(r, b, a));
+        f = set_flag_xy(f, (r >> 8) as u8);
         self.set_f(f);
+        self.wz.set(a.wrapping_add(1));
         r
     }
     // Adds thow 8-bit values, maybe with carry
@@ -578,7 +761,9 @@ impl Z80 {
         f = set_flag8(f, FLAG_Z, r == 0);
         f = set_flag8(f, FLAG_S, flag16(r, 0x8000));
         f = set_flag8(f, FLAG_H, half_carry16(a, b, r));
+        f = set_flag_xy(f, (r >> 8) as u8);
         self.set_f(f);
+        self.wz.set(a.wrapping_add(1));
         r
     }
     // Adds thow 16-bit values without carry
@@ -589,9 +774,17 @@ impl Z80 {
         f = set_flag8(f, FLAG_C, carry16(a, b, r));
         //No PV, Z, S flags!
         f = set_flag8(f, FLAG_H, half_carry16(a, b, r));
+        f = set_flag_xy(f, (r >> 8) as u8);
         self.set_f(f);
+        self.wz.set(a.wrapping_add(1));
         r
     }
+    // Compares two 8-bit values, it is a substraction but X/Y are taken from the operand
+    fn cp_flags(&mut self, a: u8, b: u8) {
+        self.sub_flags(a, b, false);
+        let f = set_flag_xy(self.f(), b);
+        self.set_f(f);
+    }
     // Increments an 8-bit value
     fn inc_flags(&mut self, a: u8) -> u8 {
         let r = a.wrapping_add(1);
@@ -653,6 +846,7 @@ impl Z80 {
         let de = self.de;
         let x = bus.peek(hl);
         bus.poke(de, x);
+        bus.internal(de, 2);
 
         match dir {
             Direction::Inc => {
@@ -670,63 +864,102 @@ impl Z80 {
         f = set_flag8(f, FLAG_N, false);
         f = set_flag8(f, FLAG_H, false);
         f = set_flag8(f, FLAG_PV, self.bc.as_u16() != 0);
+        // X and Y are bits 3 and 1 of A + (HL)
+        let n = self.a().wrapping_add(x);
+        f = set_flag8(f, FLAG_X, flag8(n, 0x08));
+        f = set_flag8(f, FLAG_Y, flag8(n, 0x02));
         self.set_f(f);
     }
     // Compare & increment/decrement (HL) and A, then decrement BC
     fn cpi_cpd(&mut self, dir: Direction, bus: &mut impl Bus) -> u8 {
         let hl = self.hl;
         let x = bus.peek(hl);
+        bus.internal(hl, 5);
         let a = self.a();
         let mut f = self.f();
 
         match dir {
-            Direction::Inc => self.hl += 1,
-            Direction::Dec => self.hl -= 1,
+            Direction::Inc => {
+                self.hl += 1;
+                self.wz += 1;
+            }
+            Direction::Dec => {
+                self.hl -= 1;
+                self.wz -= 1;
+            }
         };
         self.bc -= 1;
 
         let r = a.wrapping_sub(x);
-        f = set_flag8(f, FLAG_H, half_carry8(r, x, a));
+        let h = half_carry8(r, x, a);
+        f = set_flag8(f, FLAG_H, h);
         f = set_flag8(f, FLAG_N, true);
         f = set_flag8(f, FLAG_PV, self.bc.as_u16() != 0);
         f = set_flag_sz(f, r);
+        // X and Y are bits 3 and 1 of A - (HL) - H
+        let n = r.wrapping_sub(h as u8);
+        f = set_flag8(f, FLAG_X, flag8(n, 0x08));
+        f = set_flag8(f, FLAG_Y, flag8(n, 0x02));
         self.set_f(f);
         r
     }
     // Reads 8-bit IO port BC, store it into (HL), increment/decrement HL, then decrements B
     fn ini_ind(&mut self, dir: Direction, bus: &mut impl Bus) -> u8 {
-        let x = bus.do_in(self.bc.as_u16());
+        bus.internal(self.ir(), 1);
+        let bc = self.bc.as_u16();
+        let x = bus.do_in(bc);
         bus.poke(self.hl, x);
-        match dir {
-            Direction::Inc => self.hl += 1,
-            Direction::Dec => self.hl -= 1,
+        let c = match dir {
+            Direction::Inc => {
+                self.hl += 1;
+                self.wz.set(bc.wrapping_add(1));
+                self.c().wrapping_add(1)
+            }
+            Direction::Dec => {
+                self.hl -= 1;
+                self.wz.set(bc.wrapping_sub(1));
+                self.c().wrapping_sub(1)
+            }
         };
         // Decrement B *after* the IO
         let b = self.b().wrapping_sub(1);
         self.set_b(b);
-        let mut f = self.f();
-        f = set_flag8(f, FLAG_N | FLAG_S, flag8(b, 0x80));
-        f = set_flag8(f, FLAG_Z, b == 0);
-        self.set_f(f);
+        self.block_io_flags(x, c, b);
         b
     }
     // Reads 8-bit from (HL), writes it to IO port BC, increment/decrement HL, then decrements B
     fn outi_outd(&mut self, dir: Direction, bus: &mut impl Bus) -> u8 {
+        bus.internal(self.ir(), 1);
         let x = bus.peek(self.hl);
         // Decrement B *before* the IO
         let b = self.b().wrapping_sub(1);
         self.set_b(b);
-        bus.do_out(self.bc.as_u16(), x);
+        let bc = self.bc.as_u16();
+        bus.do_out(bc, x);
         match dir {
-            Direction::Inc => self.hl += 1,
-            Direction::Dec => self.hl -= 1,
+            Direction::Inc => {
+                self.hl += 1;
+                self.wz.set(bc.wrapping_add(1));
+            }
+            Direction::Dec => {
+                self.hl -= 1;
+                self.wz.set(bc.wrapping_sub(1));
+            }
         };
-        let mut f = self.f();
-        f = set_flag8(f, FLAG_N | FLAG_S, flag8(b, 0x80));
-        f = set_flag8(f, FLAG_Z, b == 0);
-        self.set_f(f);
+        let l = self.l();
+        self.block_io_flags(x, l, b);
         b
     }
+    // Flags of the INI/IND/OUTI/OUTD family: x is the transferred byte, k the value that is
+    // added to it (C+1, C-1 or L, depending on the instruction) and b the new B register
+    fn block_io_flags(&mut self, x: u8, k: u8, b: u8) {
+        let (n, carry) = x.overflowing_add(k);
+        let mut f = set_flag_sz(self.f(), b);
+        f = set_flag8(f, FLAG_N, flag8(x, 0x80));
+        f = set_flag8(f, FLAG_H | FLAG_C, carry);
+        f = set_flag8(f, FLAG_PV, parity((n & 0x07) ^ b));
+        self.set_f(f);
+    }
     // Decimal Arithmetic Adjust, the logic is incomprehensible
     fn daa(&mut self) {
         let a = self.a();
@@ -785,40 +1018,73 @@ impl Z80 {
     }
     // Exects the next instruction from PC, returns the consumed T-states
     pub fn exec(&mut self, bus: &mut impl Bus) -> u32 {
-        let mut opcode = match self.next_op {
+        // The T-states of the opcode fetch are included in the instruction timing, except for
+        // the 2 extra T-states of the interrupt acknowledge cycle.
+        // INT is sampled before every instruction, except just after EI. A pending NMI has priority
+        let after_ei = mem::take(&mut self.after_ei);
+        if self.int_line
+            && self.iff1
+            && !after_ei
+            && matches!(self.next_op, NextOp::Fetch | NextOp::Halt)
+        {
+            self.next_op = NextOp::Interrupt;
+        }
+        let (mut opcode, mut t) = match self.next_op {
             NextOp::Fetch => {
                 self.inc_r(bus, FetchReason::Fetch);
-                self.fetch(bus)
+                (self.fetch_opcode(bus), 0)
             }
             NextOp::Halt => {
                 self.inc_r(bus, FetchReason::Halt);
-                0x00 //NOP
+                //the fetched opcode is ignored
+                bus.fetch_opcode(self.pc);
+                bus.refresh(self.ir());
+                (0x00, 0) //NOP
             }
             NextOp::Interrupt => {
                 self.inc_r(bus, FetchReason::Interrupt);
                 self.next_op = NextOp::Fetch;
                 self.iff1 = false;
+                self.iff2 = false;
+                let data = bus.int_data_bus();
                 match self.im {
                     InterruptMode::IM0 => {
-                        log::debug!("IM0 interrupt!");
-                        0x00 //NOP
+                        //the device puts an opcode in the data bus, usually a RST
+                        //the acknowledge is an M1 cycle with 2 extra T-states
+                        bus.idle(6);
+                        (data, 2)
                     }
                     InterruptMode::IM1 => {
-                        0xff //RST 38
+                        bus.idle(6);
+                        (0xff, 2) //RST 38
                     }
                     InterruptMode::IM2 => {
-                        //assume 0xff in the data bus
-                        let v = (u16::from(self.i) << 8) | 0xff;
-                        let v = bus.peek_u16(v);
+                        //the device puts the low byte of the vector in the data bus
+                        bus.idle(7);
                         let pc = self.pc;
                         self.push(bus, pc);
+                        let v = (u16::from(self.i) << 8) | u16::from(data);
+                        let v = bus.peek_u16(v);
                         self.pc.set(v);
-                        return 0;
+                        self.wz.set(v);
+                        return 19;
                     }
                 }
             }
+            NextOp::Nmi => {
+                self.inc_r(bus, FetchReason::Interrupt);
+                self.next_op = NextOp::Fetch;
+                // IFF2 keeps the previous state, so that RETN can restore it
+                self.iff2 = self.iff1;
+                self.iff1 = false;
+                bus.idle(5);
+                let pc = self.pc;
+                self.push(bus, pc);
+                self.pc.set(0x0066);
+                self.wz = self.pc;
+                return 11;
+            }
         };
-        let mut t = 0;
         let mut prefix = XYPrefix::None;
         // The IX/IY prefix can be repeated many times to force a delay or avoid an interrupt
         let opcode = loop {
@@ -827,13 +1093,13 @@ impl Z80 {
                     prefix = XYPrefix::IX;
                     t += 4;
                     self.inc_r(bus, FetchReason::Prefix);
-                    self.fetch(bus)
+                    self.fetch_opcode(bus)
                 }
                 0xfd => {
                     prefix = XYPrefix::IY;
                     t += 4;
                     self.inc_r(bus, FetchReason::Prefix);
-                    self.fetch(bus)
+                    self.fetch_opcode(bus)
                 }
                 _ => break opcode,
             };
@@ -854,11 +1120,13 @@ impl Z80 {
                 //LD (BC),A
                 let a = self.a();
                 bus.poke(self.bc, a);
+                self.wz = R16::from_bytes(self.bc.lo().wrapping_add(1), a);
                 7
             }
             0x03 => {
                 //INC BC
                 self.bc += 1;
+                bus.internal(self.ir(), 2);
                 6
             }
             0x04 => {
@@ -890,6 +1158,7 @@ impl Z80 {
                 f = set_flag8(f, FLAG_C, b7);
                 f = set_flag8(f, FLAG_N, false);
                 f = set_flag8(f, FLAG_H, false);
+                f = set_flag_xy(f, a);
                 self.set_a(a);
                 self.set_f(f);
                 4
@@ -905,17 +1174,20 @@ impl Z80 {
                 let bc = self.bc.as_u16();
                 hl = self.add16_flags(hl, bc);
                 self.hlx_mut(prefix).set(hl);
+                bus.internal(self.ir(), 7);
                 11
             }
             0x0a => {
                 //LD A,(BC)
                 let a = bus.peek(self.bc);
                 self.set_a(a);
+                self.wz.set(self.bc.as_u16().wrapping_add(1));
                 7
             }
             0x0b => {
                 //DEC BC
                 self.bc -= 1;
+                bus.internal(self.ir(), 2);
                 6
             }
             0x0c => {
@@ -947,18 +1219,22 @@ impl Z80 {
                 f = set_flag8(f, FLAG_C, b0);
                 f = set_flag8(f, FLAG_N, false);
                 f = set_flag8(f, FLAG_H, false);
+                f = set_flag_xy(f, a);
                 self.set_a(a);
                 self.set_f(f);
                 4
             }
             0x10 => {
                 //DJNZ d
+                bus.internal(self.ir(), 1);
                 let d = self.fetch(bus);
                 let mut b = self.b();
                 b = b.wrapping_sub(1);
                 self.set_b(b);
                 if b != 0 {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 5);
                     self.pc += extend_sign(d);
+                    self.wz = self.pc;
                     13
                 } else {
                     8
@@ -974,11 +1250,13 @@ impl Z80 {
                 //LD (DE),A
                 let a = self.a();
                 bus.poke(self.de, a);
+                self.wz = R16::from_bytes(self.de.lo().wrapping_add(1), a);
                 7
             }
             0x13 => {
                 //INC DE
                 self.de += 1;
+                bus.internal(self.ir(), 2);
                 6
             }
             0x14 => {
@@ -1012,6 +1290,7 @@ impl Z80 {
                 f = set_flag8(f, FLAG_C, b7);
                 f = set_flag8(f, FLAG_N, false);
                 f = set_flag8(f, FLAG_H, false);
+                f = set_flag_xy(f, a);
                 self.set_a(a);
                 self.set_f(f);
                 4
@@ -1019,7 +1298,9 @@ impl Z80 {
             0x18 => {
                 //JR d
                 let d = self.fetch(bus);
+                bus.internal(self.pc.as_u16().wrapping_sub(1), 5);
                 self.pc += extend_sign(d);
+                self.wz = self.pc;
                 12
             }
             0x19 => {
@@ -1028,17 +1309,20 @@ impl Z80 {
                 let de = self.de.as_u16();
                 hl = self.add16_flags(hl, de);
                 self.hlx_mut(prefix).set(hl);
+                bus.internal(self.ir(), 7);
                 11
             }
             0x1a => {
                 //LD A,(DE)
                 let a = bus.peek(self.de);
                 self.set_a(a);
+                self.wz.set(self.de.as_u16().wrapping_add(1));
                 7
             }
             0x1b => {
                 //DEC DE
                 self.de -= 1;
+                bus.internal(self.ir(), 2);
                 6
             }
             0x1c => {
@@ -1072,6 +1356,7 @@ impl Z80 {
                 f = set_flag8(f, FLAG_C, b0);
                 f = set_flag8(f, FLAG_N, false);
                 f = set_flag8(f, FLAG_H, false);
+                f = set_flag_xy(f, a);
                 self.set_a(a);
                 self.set_f(f);
                 4
@@ -1080,9 +1365,13 @@ impl Z80 {
                 //JR NZ,d
                 let d = self.fetch(bus);
                 if !flag8(self.f(), FLAG_Z) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 5);
                     self.pc += extend_sign(d);
+                    self.wz = self.pc;
+                    12
+                } else {
+                    7
                 }
-                12
             }
             0x21 => {
                 //LD HL,nn
@@ -1094,11 +1383,13 @@ impl Z80 {
                 //LD (nn),HL
                 let addr = self.fetch_u16(bus);
                 bus.poke_u16(addr, self.hlx(prefix).as_u16());
+                self.wz.set(addr.wrapping_add(1));
                 16
             }
             0x23 => {
                 //INC HL
                 *self.hlx_mut(prefix) += 1;
+                bus.internal(self.ir(), 2);
                 6
             }
             0x24 => {
@@ -1130,15 +1421,20 @@ impl Z80 {
                 //JR Z,d
                 let d = self.fetch(bus);
                 if flag8(self.f(), FLAG_Z) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 5);
                     self.pc += extend_sign(d);
+                    self.wz = self.pc;
+                    12
+                } else {
+                    7
                 }
-                12
             }
             0x29 => {
                 //ADD HL,HL
                 let mut hl = self.hlx(prefix).as_u16();
                 hl = self.add16_flags(hl, hl);
                 self.hlx_mut(prefix).set(hl);
+                bus.internal(self.ir(), 7);
                 11
             }
             0x2a => {
@@ -1146,11 +1442,13 @@ impl Z80 {
                 let addr = self.fetch_u16(bus);
                 let d = bus.peek_u16(addr);
                 self.hlx_mut(prefix).set(d);
+                self.wz.set(addr.wrapping_add(1));
                 16
             }
             0x2b => {
                 //DEC HL
                 *self.hlx_mut(prefix) -= 1;
+                bus.internal(self.ir(), 2);
                 6
             }
             0x2c => {
@@ -1180,6 +1478,7 @@ impl Z80 {
                 a ^= 0xff;
                 f = set_flag8(f, FLAG_H, true);
                 f = set_flag8(f, FLAG_N, true);
+                f = set_flag_xy(f, a);
                 self.set_a(a);
                 self.set_f(f);
                 4
@@ -1188,9 +1487,13 @@ impl Z80 {
                 //JR NC,d
                 let d = self.fetch(bus);
                 if !flag8(self.f(), FLAG_C) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 5);
                     self.pc += extend_sign(d);
+                    self.wz = self.pc;
+                    12
+                } else {
+                    7
                 }
-                12
             }
             0x31 => {
                 //LD SP,nn
@@ -1201,12 +1504,15 @@ impl Z80 {
             0x32 => {
                 //LD (nn),A
                 let addr = self.fetch_u16(bus);
-                bus.poke(addr, self.a());
+                let a = self.a();
+                bus.poke(addr, a);
+                self.wz = R16::from_bytes((addr as u8).wrapping_add(1), a);
                 13
             }
             0x33 => {
                 //INC SP
                 self.sp += 1;
+                bus.internal(self.ir(), 2);
                 6
             }
             0x34 => {
@@ -1214,6 +1520,7 @@ impl Z80 {
                 let (addr, t) = self.hlx_addr(prefix, bus);
                 let mut b = bus.peek(addr);
                 b = self.inc_flags(b);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
                 11 + t
             }
@@ -1222,13 +1529,27 @@ impl Z80 {
                 let (addr, t) = self.hlx_addr(prefix, bus);
                 let mut b = bus.peek(addr);
                 b = self.dec_flags(b);
+                bus.internal(addr, 1);
                 bus.poke(addr, b);
                 11 + t
             }
             0x36 => {
                 //LD (HL),n
-                let (addr, t) = self.hlx_addr(prefix, bus);
-                let n = self.fetch(bus);
+                // With IX/IY the immediate value comes after the displacement
+                let (addr, n, t) = match prefix {
+                    XYPrefix::None => {
+                        let n = self.fetch(bus);
+                        (self.hl.as_u16(), n, 0)
+                    }
+                    _ => {
+                        let d = self.fetch(bus);
+                        let n = self.fetch(bus);
+                        bus.internal(self.pc.as_u16().wrapping_sub(1), 2);
+                        let addr = self.hlx(prefix).as_u16().wrapping_add(extend_sign(d));
+                        self.wz.set(addr);
+                        (addr, n, 5)
+                    }
+                };
                 bus.poke(addr, n);
                 10 + t
             }
@@ -1239,15 +1560,20 @@ impl Z80 {
                 f = set_flag8(f, FLAG_H, false);
                 f = set_flag8(f, FLAG_C, true);
                 self.set_f(f);
+                self.set_flag_xy_from_a();
                 4
             }
             0x38 => {
                 //JR C,d
                 let d = self.fetch(bus);
                 if flag8(self.f(), FLAG_C) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 5);
                     self.pc += extend_sign(d);
+                    self.wz = self.pc;
+                    12
+                } else {
+                    7
                 }
-                12
             }
             0x39 => {
                 //ADD HL,SP
@@ -1255,6 +1581,7 @@ impl Z80 {
                 let sp = self.sp.as_u16();
                 hl = self.add16_flags(hl, sp);
                 self.hlx_mut(prefix).set(hl);
+                bus.internal(self.ir(), 7);
                 11
             }
             0x3a => {
@@ -1262,11 +1589,13 @@ impl Z80 {
                 let addr = self.fetch_u16(bus);
                 let x = bus.peek(addr);
                 self.set_a(x);
+                self.wz.set(addr.wrapping_add(1));
                 13
             }
             0x3b => {
                 //DEC SP
                 self.sp -= 1;
+                bus.internal(self.ir(), 2);
                 6
             }
             0x3c => {
@@ -1297,6 +1626,7 @@ impl Z80 {
                 f = set_flag8(f, FLAG_H, c);
                 f = set_flag8(f, FLAG_C, !c);
                 self.set_f(f);
+                self.set_flag_xy_from_a();
                 4
             }
             0x40 => {
@@ -1628,7 +1958,7 @@ impl Z80 {
             0x76 => {
                 //HALT
                 if !self.iff1 {
-                    log::warn!("DI/HALT deadlock!");
+                    log::warn!("HALT with interrupts disabled, only NMI can resume");
                 }
                 self.next_op = NextOp::Halt;
                 4
@@ -1742,7 +2072,7 @@ impl Z80 {
                 let r = bus.peek(addr);
                 let a = self.add_flags(a, r, false);
                 self.set_a(a);
-                4 + t
+                7 + t
             }
             0x87 => {
                 //ADD A
@@ -1806,7 +2136,7 @@ impl Z80 {
                 let r = bus.peek(addr);
                 let a = self.add_flags(a, r, true);
                 self.set_a(a);
-                4 + t
+                7 + t
             }
             0x8f => {
                 //ADC A
@@ -1870,7 +2200,7 @@ impl Z80 {
                 let r = bus.peek(addr);
                 let a = self.sub_flags(a, r, false);
                 self.set_a(a);
-                4 + t
+                7 + t
             }
             0x97 => {
                 //SUB A
@@ -1934,7 +2264,7 @@ impl Z80 {
                 let r = bus.peek(addr);
                 let a = self.sub_flags(a, r, true);
                 self.set_a(a);
-                4 + t
+                7 + t
             }
             0x9f => {
                 //SBC A
@@ -1998,7 +2328,7 @@ impl Z80 {
                 let r = bus.peek(addr);
                 let a = self.and_flags(a, r);
                 self.set_a(a);
-                4 + t
+                7 + t
             }
             0xa7 => {
                 //AND A
@@ -2062,7 +2392,7 @@ impl Z80 {
                 let r = bus.peek(addr);
                 let a = self.xor_flags(a, r);
                 self.set_a(a);
-                4 + t
+                7 + t
             }
             0xaf => {
                 //XOR A
@@ -2126,7 +2456,7 @@ impl Z80 {
                 let r = bus.peek(addr);
                 let a = self.or_flags(a, r);
                 self.set_a(a);
-                4 + t
+                7 + t
             }
             0xb7 => {
                 //OR A
@@ -2139,42 +2469,42 @@ impl Z80 {
                 //CP B
                 let a = self.a();
                 let r = self.b();
-                self.sub_flags(a, r, false);
+                self.cp_flags(a, r);
                 4
             }
             0xb9 => {
                 //CP C
                 let a = self.a();
                 let r = self.c();
-                self.sub_flags(a, r, false);
+                self.cp_flags(a, r);
                 4
             }
             0xba => {
                 //CP D
                 let a = self.a();
                 let r = self.d();
-                self.sub_flags(a, r, false);
+                self.cp_flags(a, r);
                 4
             }
             0xbb => {
                 //CP E
                 let a = self.a();
                 let r = self.e();
-                self.sub_flags(a, r, false);
+                self.cp_flags(a, r);
                 4
             }
             0xbc => {
                 //CP H
                 let a = self.a();
                 let r = self.hx(prefix);
-                self.sub_flags(a, r, false);
+                self.cp_flags(a, r);
                 4
             }
             0xbd => {
                 //CP L
                 let a = self.a();
                 let r = self.lx(prefix);
-                self.sub_flags(a, r, false);
+                self.cp_flags(a, r);
                 4
             }
             0xbe => {
@@ -2182,20 +2512,22 @@ impl Z80 {
                 let a = self.a();
                 let (addr, t) = self.hlx_addr(prefix, bus);
                 let r = bus.peek(addr);
-                self.sub_flags(a, r, false);
-                4 + t
+                self.cp_flags(a, r);
+                7 + t
             }
             0xbf => {
                 //CP A
                 let a = self.a();
-                self.sub_flags(a, a, false);
+                self.cp_flags(a, a);
                 4
             }
             0xc0 => {
                 //RET NZ
+                bus.internal(self.ir(), 1);
                 if !flag8(self.f(), FLAG_Z) {
                     let pc = self.pop(bus);
                     self.pc.set(pc);
+                    self.wz.set(pc);
                     11
                 } else {
                     5
@@ -2210,6 +2542,7 @@ impl Z80 {
             0xc2 => {
                 //JP NZ,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if !flag8(self.f(), FLAG_Z) {
                     self.pc.set(addr);
                 }
@@ -2219,12 +2552,15 @@ impl Z80 {
                 //JP nn
                 let pc = self.fetch_u16(bus);
                 self.pc.set(pc);
+                self.wz.set(pc);
                 10
             }
             0xc4 => {
                 //CALL NZ,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if !flag8(self.f(), FLAG_Z) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 1);
                     let pc = self.pc;
                     self.push(bus, pc);
                     self.pc.set(addr);
@@ -2236,6 +2572,7 @@ impl Z80 {
             0xc5 => {
                 //PUSH BC
                 let bc = self.bc;
+                bus.internal(self.ir(), 1);
                 self.push(bus, bc);
                 11
             }
@@ -2250,15 +2587,19 @@ impl Z80 {
             0xc7 => {
                 //RST 00
                 let pc = self.pc;
+                bus.internal(self.ir(), 1);
                 self.push(bus, pc);
                 self.pc.set(0x00);
+                self.wz.set(0x00);
                 11
             }
             0xc8 => {
                 //RET Z
+                bus.internal(self.ir(), 1);
                 if flag8(self.f(), FLAG_Z) {
                     let pc = self.pop(bus);
                     self.pc.set(pc);
+                    self.wz.set(pc);
                     11
                 } else {
                     5
@@ -2268,11 +2609,13 @@ impl Z80 {
                 //RET
                 let pc = self.pop(bus);
                 self.pc.set(pc);
+                self.wz.set(pc);
                 10
             }
             0xca => {
                 //JP Z,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if flag8(self.f(), FLAG_Z) {
                     self.pc.set(addr);
                 }
@@ -2282,7 +2625,9 @@ impl Z80 {
             0xcc => {
                 //CALL Z,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if flag8(self.f(), FLAG_Z) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 1);
                     let pc = self.pc;
                     self.push(bus, pc);
                     self.pc.set(addr);
@@ -2294,6 +2639,8 @@ impl Z80 {
             0xcd => {
                 //CALL nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
+                bus.internal(self.pc.as_u16().wrapping_sub(1), 1);
                 let pc = self.pc;
                 self.push(bus, pc);
                 self.pc.set(addr);
@@ -2310,15 +2657,19 @@ impl Z80 {
             0xcf => {
                 //RST 08
                 let pc = self.pc;
+                bus.internal(self.ir(), 1);
                 self.push(bus, pc);
                 self.pc.set(0x08);
+                self.wz.set(0x08);
                 11
             }
             0xd0 => {
                 //RET NC
+                bus.internal(self.ir(), 1);
                 if !flag8(self.f(), FLAG_C) {
                     let pc = self.pop(bus);
                     self.pc.set(pc);
+                    self.wz.set(pc);
                     11
                 } else {
                     5
@@ -2333,6 +2684,7 @@ impl Z80 {
             0xd2 => {
                 //JP NC,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if !flag8(self.f(), FLAG_C) {
                     self.pc.set(addr);
                 }
@@ -2342,14 +2694,16 @@ impl Z80 {
                 //OUT (n),A
                 let n = self.fetch(bus);
                 let a = self.a();
-                let n = (u16::from(a) << 8) | u16::from(n);
-                bus.do_out(n, a);
+                bus.do_out(R16::from_bytes(n, a), a);
+                self.wz = R16::from_bytes(n.wrapping_add(1), a);
                 11
             }
             0xd4 => {
                 //CALL NC,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if !flag8(self.f(), FLAG_C) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 1);
                     let pc = self.pc;
                     self.push(bus, pc);
                     self.pc.set(addr);
@@ -2361,6 +2715,7 @@ impl Z80 {
             0xd5 => {
                 //PUSH DE
                 let de = self.de;
+                bus.internal(self.ir(), 1);
                 self.push(bus, de);
                 11
             }
@@ -2375,15 +2730,19 @@ impl Z80 {
             0xd7 => {
                 //RST 10
                 let pc = self.pc;
+                bus.internal(self.ir(), 1);
                 self.push(bus, pc);
                 self.pc.set(0x10);
+                self.wz.set(0x10);
                 11
             }
             0xd8 => {
                 //RET C
+                bus.internal(self.ir(), 1);
                 if flag8(self.f(), FLAG_C) {
                     let pc = self.pop(bus);
                     self.pc.set(pc);
+                    self.wz.set(pc);
                     11
                 } else {
                     5
@@ -2399,6 +2758,7 @@ impl Z80 {
             0xda => {
                 //JP C,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if flag8(self.f(), FLAG_C) {
                     self.pc.set(addr);
                 }
@@ -2411,12 +2771,15 @@ impl Z80 {
                 let port = (u16::from(a) << 8) | u16::from(n);
                 let a = bus.do_in(port);
                 self.set_a(a);
+                self.wz.set(port.wrapping_add(1));
                 11
             }
             0xdc => {
                 //CALL C,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if flag8(self.f(), FLAG_C) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 1);
                     let pc = self.pc;
                     self.push(bus, pc);
                     self.pc.set(addr);
@@ -2440,15 +2803,19 @@ impl Z80 {
             0xdf => {
                 //RST 18
                 let pc = self.pc;
+                bus.internal(self.ir(), 1);
                 self.push(bus, pc);
                 self.pc.set(0x18);
+                self.wz.set(0x18);
                 11
             }
             0xe0 => {
                 //RET PO
+                bus.internal(self.ir(), 1);
                 if !flag8(self.f(), FLAG_PV) {
                     let pc = self.pop(bus);
                     self.pc.set(pc);
+                    self.wz.set(pc);
                     11
                 } else {
                     5
@@ -2463,6 +2830,7 @@ impl Z80 {
             0xe2 => {
                 //JP PO,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if !flag8(self.f(), FLAG_PV) {
                     self.pc.set(addr);
                 }
@@ -2470,15 +2838,24 @@ impl Z80 {
             }
             0xe3 => {
                 //EX (SP),HL
-                let x = bus.peek_u16(self.sp);
-                bus.poke_u16(self.sp, self.hlx(prefix).as_u16());
+                let sp = self.sp.as_u16();
+                let sp1 = sp.wrapping_add(1);
+                let x = bus.peek_u16(sp);
+                bus.internal(sp1, 1);
+                let hl = self.hlx(prefix);
+                bus.poke(sp1, hl.hi());
+                bus.poke(sp, hl.lo());
+                bus.internal(sp, 2);
                 self.hlx_mut(prefix).set(x);
+                self.wz.set(x);
                 19
             }
             0xe4 => {
                 //CALL PO,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if !flag8(self.f(), FLAG_PV) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 1);
                     let pc = self.pc;
                     self.push(bus, pc);
                     self.pc.set(addr);
@@ -2490,6 +2867,7 @@ impl Z80 {
             0xe5 => {
                 //PUSH HL
                 let hl = self.hlx(prefix);
+                bus.internal(self.ir(), 1);
                 self.push(bus, hl);
                 11
             }
@@ -2504,15 +2882,19 @@ impl Z80 {
             0xe7 => {
                 //RST 20
                 let pc = self.pc;
+                bus.internal(self.ir(), 1);
                 self.push(bus, pc);
                 self.pc.set(0x20);
+                self.wz.set(0x20);
                 11
             }
             0xe8 => {
                 //RET PE
+                bus.internal(self.ir(), 1);
                 if flag8(self.f(), FLAG_PV) {
                     let pc = self.pop(bus);
                     self.pc.set(pc);
+                    self.wz.set(pc);
                     11
                 } else {
                     5
@@ -2526,6 +2908,7 @@ impl Z80 {
             0xea => {
                 //JP PE,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if flag8(self.f(), FLAG_PV) {
                     self.pc.set(addr);
                 }
@@ -2539,7 +2922,9 @@ impl Z80 {
             0xec => {
                 //CALL PE,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if flag8(self.f(), FLAG_PV) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 1);
                     let pc = self.pc;
                     self.push(bus, pc);
                     self.pc.set(addr);
@@ -2560,15 +2945,19 @@ impl Z80 {
             0xef => {
                 //RST 28
                 let pc = self.pc;
+                bus.internal(self.ir(), 1);
                 self.push(bus, pc);
                 self.pc.set(0x28);
+                self.wz.set(0x28);
                 11
             }
             0xf0 => {
                 //RET P
+                bus.internal(self.ir(), 1);
                 if !flag8(self.f(), FLAG_S) {
                     let pc = self.pop(bus);
                     self.pc.set(pc);
+                    self.wz.set(pc);
                     11
                 } else {
                     5
@@ -2583,6 +2972,7 @@ impl Z80 {
             0xf2 => {
                 //JP P,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if !flag8(self.f(), FLAG_S) {
                     self.pc.set(addr);
                 }
@@ -2591,12 +2981,15 @@ impl Z80 {
             0xf3 => {
                 //DI
                 self.iff1 = false;
+                self.iff2 = false;
                 4
             }
             0xf4 => {
                 //CALL P,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if !flag8(self.f(), FLAG_S) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 1);
                     let pc = self.pc;
                     self.push(bus, pc);
                     self.pc.set(addr);
@@ -2608,6 +3001,7 @@ impl Z80 {
             0xf5 => {
                 //PUSH AF
                 let af = self.af;
+                bus.internal(self.ir(), 1);
                 self.push(bus, af);
                 11
             }
@@ -2622,15 +3016,19 @@ impl Z80 {
             0xf7 => {
                 //RST 30
                 let pc = self.pc;
+                bus.internal(self.ir(), 1);
                 self.push(bus, pc);
                 self.pc.set(0x30);
+                self.wz.set(0x30);
                 11
             }
             0xf8 => {
                 //RET M
+                bus.internal(self.ir(), 1);
                 if flag8(self.f(), FLAG_S) {
                     let pc = self.pop(bus);
                     self.pc.set(pc);
+                    self.wz.set(pc);
                     11
                 } else {
                     5
@@ -2639,11 +3037,13 @@ impl Z80 {
             0xf9 => {
                 //LD SP,HL
                 self.sp = self.hlx(prefix);
+                bus.internal(self.ir(), 2);
                 6
             }
             0xfa => {
                 //JP M,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if flag8(self.f(), FLAG_S) {
                     self.pc.set(addr);
                 }
@@ -2652,12 +3052,16 @@ impl Z80 {
             0xfb => {
                 //EI
                 self.iff1 = true;
+                self.iff2 = true;
+                self.after_ei = true;
                 4
             }
             0xfc => {
                 //CALL M,nn
                 let addr = self.fetch_u16(bus);
+                self.wz.set(addr);
                 if flag8(self.f(), FLAG_S) {
+                    bus.internal(self.pc.as_u16().wrapping_sub(1), 1);
                     let pc = self.pc;
                     self.push(bus, pc);
                     self.pc.set(addr);
@@ -2674,14 +3078,16 @@ impl Z80 {
                 //CP n
                 let n = self.fetch(bus);
                 let a = self.a();
-                self.sub_flags(a, n, false);
+                self.cp_flags(a, n);
                 7
             }
             0xff => {
                 //RST 38
                 let pc = self.pc;
+                bus.internal(self.ir(), 1);
                 self.push(bus, pc);
                 self.pc.set(0x38);
+                self.wz.set(0x38);
                 11
             }
         };
@@ -2691,3 +3097,287 @@ impl Z80 {
 
 #[cfg(feature = "dump_ops")]
 mod dumps;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A flat 64K RAM, with no IO devices, that counts the T-states of the bus cycles
+    struct TestBus {
+        mem: Vec<u8>,
+        int_data: u8,
+        cycles: u32,
+    }
+
+    impl TestBus {
+        fn new(code: &[u8]) -> TestBus {
+            let mut mem = vec![0; 0x10000];
+            mem[..code.len()].copy_from_slice(code);
+            TestBus {
+                mem,
+                int_data: 0xff,
+                cycles: 0,
+            }
+        }
+    }
+
+    impl Bus for TestBus {
+        fn peek(&mut self, addr: impl Into<u16>) -> u8 {
+            self.cycles += 3;
+            self.mem[usize::from(addr.into())]
+        }
+        fn poke(&mut self, addr: impl Into<u16>, value: u8) {
+            self.cycles += 3;
+            self.mem[usize::from(addr.into())] = value;
+        }
+        fn do_in(&mut self, _port: impl Into<u16>) -> u8 {
+            self.cycles += 4;
+            0xff
+        }
+        fn do_out(&mut self, _port: impl Into<u16>, _value: u8) {
+            self.cycles += 4;
+        }
+        fn fetch_opcode(&mut self, addr: impl Into<u16>) -> u8 {
+            self.cycles += 4;
+            self.mem[usize::from(addr.into())]
+        }
+        fn internal(&mut self, _addr: impl Into<u16>, t: u32) {
+            self.cycles += t;
+        }
+        fn idle(&mut self, t: u32) {
+            self.cycles += t;
+        }
+        fn int_data_bus(&mut self) -> u8 {
+            self.int_data
+        }
+    }
+
+    fn run(z80: &mut Z80, bus: &mut TestBus, n: usize) {
+        for _ in 0..n {
+            z80.exec(bus);
+        }
+    }
+
+    #[test]
+    fn bit_hl_xy_from_memptr() {
+        // LD A,(0x2812); LD HL,0; BIT 0,(HL)
+        let mut bus = TestBus::new(&[0x3a, 0x12, 0x28, 0x21, 0x00, 0x00, 0xcb, 0x46]);
+        let mut z80 = Z80::new();
+        run(&mut z80, &mut bus, 3);
+        assert_eq!(z80.wz.as_u16(), 0x2813);
+        assert_eq!(z80.f() & (FLAG_X | FLAG_Y), 0x28);
+    }
+
+    #[test]
+    fn scf_xy_from_a() {
+        // LD A,0x28; SCF; XOR A; SCF
+        let mut bus = TestBus::new(&[0x3e, 0x28, 0x37, 0xaf, 0x37]);
+        let mut z80 = Z80::new();
+        run(&mut z80, &mut bus, 2);
+        assert_eq!(z80.f() & (FLAG_X | FLAG_Y), 0x28);
+        run(&mut z80, &mut bus, 2);
+        assert_eq!(z80.f() & (FLAG_X | FLAG_Y), 0x00);
+    }
+
+    #[test]
+    fn cpi_xy_flags() {
+        // LD A,0x10; LD HL,0x0100; LD BC,1; CPI
+        let mut bus = TestBus::new(&[0x3e, 0x10, 0x21, 0x00, 0x01, 0x01, 0x01, 0x00, 0xed, 0xa1]);
+        bus.mem[0x100] = 0x01;
+        let mut z80 = Z80::new();
+        run(&mut z80, &mut bus, 4);
+        // 0x10 - 0x01 - H = 0x0e
+        assert_eq!(z80.f() & (FLAG_X | FLAG_Y), 0x28);
+        assert_eq!(z80.wz.as_u16(), 0x0001);
+    }
+
+    #[test]
+    fn nmi_and_retn() {
+        // LD SP,0x8000; EI; NOP
+        let mut bus = TestBus::new(&[0x31, 0x00, 0x80, 0xfb, 0x00]);
+        // RETN
+        bus.mem[0x66..0x68].copy_from_slice(&[0xed, 0x45]);
+        let mut z80 = Z80::new();
+        run(&mut z80, &mut bus, 2);
+        z80.nmi();
+        run(&mut z80, &mut bus, 1);
+        assert_eq!(z80.pc.as_u16(), 0x0066);
+        assert_eq!(bus.peek_u16(0x7ffe_u16), 0x0004);
+        assert!(!z80.iff1);
+        assert!(z80.iff2);
+        run(&mut z80, &mut bus, 1);
+        assert_eq!(z80.pc.as_u16(), 0x0004);
+        assert!(z80.iff1);
+    }
+
+    #[test]
+    fn snapshot_iff2() {
+        // LD SP,0x8000; EI; NOP
+        let mut bus = TestBus::new(&[0x31, 0x00, 0x80, 0xfb, 0x00]);
+        // LD A,I
+        bus.mem[0x66..0x68].copy_from_slice(&[0xed, 0x57]);
+        let mut z80 = Z80::new();
+        run(&mut z80, &mut bus, 2);
+        z80.nmi();
+        run(&mut z80, &mut bus, 1);
+
+        let mut data = [0; 30];
+        z80.snapshot(&mut data);
+        let (mut z80, version) = Z80::load_snapshot(&data).unwrap();
+        assert_eq!(version, Z80FileVersion::V1);
+        assert!(!z80.iff1);
+        assert!(z80.iff2);
+        run(&mut z80, &mut bus, 1);
+        assert!(flag8(z80.f(), FLAG_PV));
+    }
+
+    #[test]
+    fn interrupt_data_bus() {
+        // LD SP,0x8000; LD A,0x12; LD I,A; IM 2; EI; NOP
+        let code = [
+            0x31, 0x00, 0x80, 0x3e, 0x12, 0xed, 0x47, 0xed, 0x5e, 0xfb, 0x00,
+        ];
+        let mut bus = TestBus::new(&code);
+        bus.int_data = 0x40;
+        bus.mem[0x1240..0x1242].copy_from_slice(&[0x34, 0x12]);
+        let mut z80 = Z80::new();
+        run(&mut z80, &mut bus, 6);
+        z80.set_int(true);
+        assert_eq!(z80.exec(&mut bus), 19);
+        assert_eq!(z80.pc.as_u16(), 0x1234);
+
+        // IM 0 with RST 28 in the data bus
+        let mut bus = TestBus::new(&[0x31, 0x00, 0x80, 0xfb, 0x00]);
+        bus.int_data = 0xef;
+        let mut z80 = Z80::new();
+        run(&mut z80, &mut bus, 3);
+        z80.set_int(true);
+        assert_eq!(z80.exec(&mut bus), 13);
+        assert_eq!(z80.pc.as_u16(), 0x0028);
+    }
+
+    #[test]
+    fn registers_roundtrip() {
+        // HALT
+        let mut bus = TestBus::new(&[0x76]);
+        let mut z80 = Z80::new();
+        run(&mut z80, &mut bus, 1);
+        let mut regs = z80.registers();
+        assert!(regs.halted);
+        assert_eq!(regs.pc, 0x0001);
+
+        regs.halted = false;
+        regs.pc = 0x1234;
+        regs.r = 0x85;
+        regs.im = InterruptMode::IM2;
+        z80.set_registers(&regs);
+        assert_eq!(z80.registers(), regs);
+        run(&mut z80, &mut bus, 1);
+        assert_eq!(z80.registers().pc, 0x1235);
+        assert_eq!(z80.registers().r, 0x86);
+    }
+
+    // Runs a single instruction, checks that the bus cycles add up to the returned T-states
+    fn exec_timed(code: &[u8], f: u8, bc: u16) -> u32 {
+        let mut bus = TestBus::new(code);
+        let mut z80 = Z80::new();
+        z80.sp.set(0x8000);
+        z80.bc.set(bc);
+        z80.set_f(f);
+        let t = z80.exec(&mut bus);
+        assert_eq!(
+            bus.cycles, t,
+            "code {:02x?} f={:02x} bc={:04x}",
+            code, f, bc
+        );
+        t
+    }
+
+    #[test]
+    fn bus_cycles() {
+        for (f, bc) in [(0x00, 0x0102), (0xff, 0x0201)] {
+            for op in 0..=0xff {
+                exec_timed(&[op, 0x05, 0x10], f, bc);
+                exec_timed(&[0xcb, op], f, bc);
+                exec_timed(&[0xed, op, 0x05, 0x10], f, bc);
+                exec_timed(&[0xdd, op, 0x05, 0x10], f, bc);
+                exec_timed(&[0xfd, op, 0x05, 0x10], f, bc);
+                exec_timed(&[0xdd, 0xcb, 0x05, op], f, bc);
+                exec_timed(&[0xfd, 0xcb, 0x05, op], f, bc);
+            }
+        }
+        for im in [0x46, 0x56, 0x5e] {
+            let mut bus = TestBus::new(&[0xed, im, 0xfb, 0x00]);
+            let mut z80 = Z80::new();
+            run(&mut z80, &mut bus, 3);
+            z80.set_int(true);
+            bus.cycles = 0;
+            let t = z80.exec(&mut bus);
+            assert_eq!((bus.cycles, t), (t, if im == 0x5e { 19 } else { 13 }));
+        }
+        let mut bus = TestBus::new(&[]);
+        let mut z80 = Z80::new();
+        z80.nmi();
+        assert_eq!(z80.exec(&mut bus), 11);
+        assert_eq!(bus.cycles, 11);
+    }
+
+    #[test]
+    fn instruction_timings() {
+        // ADD A,(HL)
+        assert_eq!(exec_timed(&[0x86], 0x00, 0), 7);
+        // JR NZ,d not taken / taken
+        assert_eq!(exec_timed(&[0x20, 0x10], FLAG_Z, 0), 7);
+        assert_eq!(exec_timed(&[0x20, 0x10], 0x00, 0), 12);
+        // RLC (HL), BIT 0,(HL)
+        assert_eq!(exec_timed(&[0xcb, 0x06], 0x00, 0), 15);
+        assert_eq!(exec_timed(&[0xcb, 0x46], 0x00, 0), 12);
+        // LD (IX+d),n
+        assert_eq!(exec_timed(&[0xdd, 0x36, 0x01, 0x02], 0x00, 0), 19);
+        // ADD A,(IX+d), INC (IX+d)
+        assert_eq!(exec_timed(&[0xdd, 0x86, 0x01], 0x00, 0), 19);
+        assert_eq!(exec_timed(&[0xdd, 0x34, 0x01], 0x00, 0), 23);
+        // RLC (IX+d), BIT 0,(IX+d)
+        assert_eq!(exec_timed(&[0xdd, 0xcb, 0x01, 0x06], 0x00, 0), 23);
+        assert_eq!(exec_timed(&[0xdd, 0xcb, 0x01, 0x46], 0x00, 0), 20);
+        // EX (SP),IX
+        assert_eq!(exec_timed(&[0xdd, 0xe3], 0x00, 0), 23);
+    }
+
+    #[test]
+    fn xycb_copy_to_register() {
+        // LD IX,0x100; SET 0,(IX+1),B
+        let mut bus = TestBus::new(&[0xdd, 0x21, 0x00, 0x01, 0xdd, 0xcb, 0x01, 0xc0]);
+        bus.mem[0x101] = 0x80;
+        let mut z80 = Z80::new();
+        run(&mut z80, &mut bus, 2);
+        assert_eq!(bus.mem[0x101], 0x81);
+        assert_eq!(z80.b(), 0x81);
+    }
+
+    #[test]
+    fn int_line() {
+        // LD SP,0x8000; IM 1; EI; NOP; NOP
+        let code = [0x31, 0x00, 0x80, 0xed, 0x56, 0xfb, 0x00, 0x00];
+        let mut bus = TestBus::new(&code);
+        let mut z80 = Z80::new();
+        run(&mut z80, &mut bus, 2);
+        // Not accepted while disabled, nor just after EI
+        z80.set_int(true);
+        run(&mut z80, &mut bus, 1);
+        assert_eq!(z80.pc.as_u16(), 0x0006);
+        run(&mut z80, &mut bus, 1);
+        assert_eq!(z80.pc.as_u16(), 0x0007);
+        run(&mut z80, &mut bus, 1);
+        assert_eq!(z80.pc.as_u16(), 0x0038);
+
+        // The interrupt is lost if INT goes inactive before EI
+        let mut bus = TestBus::new(&code);
+        let mut z80 = Z80::new();
+        z80.set_int(true);
+        run(&mut z80, &mut bus, 2);
+        z80.set_int(false);
+        run(&mut z80, &mut bus, 3);
+        assert_eq!(z80.pc.as_u16(), 0x0008);
+    }
+}
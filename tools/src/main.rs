@@ -21,6 +21,10 @@ impl raze::Gui for ConsoleGui {
 
     const PALETTE: [[u8; 8]; 2] = [[0, 1, 2, 3, 4, 5, 6, 7], [8, 9, 10, 11, 12, 13, 14, 15]];
 
+    fn rgb(_r: u8, _g: u8, _b: u8) -> u8 {
+        0
+    }
+
     fn on_rzx_running(&mut self, _running: bool, _percent: u32) {}
 
     fn on_tape_block(&mut self, _index: usize) {}
@@ -58,6 +62,27 @@ fn main() -> anyhow::Result<()> {
             let mut z80 = Z80::new();
             z80.dump_daa();
         }
+        "trace" => {
+            let file = args
+                .next()
+                .ok_or_else(|| anyhow!("Missing snapshot file"))?;
+            let count: usize = match args.next() {
+                Some(n) => n.parse()?,
+                None => 1000,
+            };
+            let snap = std::fs::read(file)?;
+            let mut game = raze::Game::load_snapshot(&snap, &mut ConsoleGui)?;
+
+            for _ in 0..count {
+                let r = game.registers();
+                let (text, _) = game.disassemble(r.pc);
+                println!(
+                    "{:04x} {:<20} AF={:04x} BC={:04x} DE={:04x} HL={:04x} IX={:04x} IY={:04x} SP={:04x}",
+                    r.pc, text, r.af, r.bc, r.de, r.hl, r.ix, r.iy, r.sp
+                );
+                game.step_instruction(&mut ConsoleGui);
+            }
+        }
         file => {
             let snap = std::fs::read(file)?;
             //dbg!(rzx::Rzx::new(&mut &snap[..])?);
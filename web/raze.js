@@ -4,6 +4,15 @@ import * as base64 from "./base64.js";
 
 let g_game;
 let g_model;
+let g_stereo = 0; //0: mono, 1: ABC, 2: ACB
+let g_ym = false;
+let g_turboSound = false;
+let g_border = 1; //0: none, 1: small, 2: normal, 3: full
+let g_ulaplus = false;
+let g_snow = true;
+//speaker filter cutoffs in Hz, the defaults of the emulator
+let g_lowPass = 15000;
+let g_highPass = 20;
 let g_actx = new (window.AudioContext || window.webkitAudioContext)();
 let g_audio_next = 0;
 let g_turbo = false;
@@ -94,15 +103,16 @@ export function onRZXRunning(isRunning, percent) {
 
 export function putSoundData(slice) {
     let asrc = g_actx.createBufferSource();
-    //Safari cannot use random frequencies so go with a standard 22.05 kHz
-    let freq = 22050;
-    let abuf = g_actx.createBuffer(1, slice.length, freq);
-    if (abuf.copyToChannel) {
-        abuf.copyToChannel(slice, 0);
-    } else {
-        let data = abuf.getChannelData(0);
-        for (let i = 0; i < slice.length; ++i)
-            data[i] = slice[i];
+    //The game generates the audio at the rate of the context, so no resampling is needed
+    let freq = g_actx.sampleRate;
+    //The slice is stereo, left and right samples interleaved
+    let len = slice.length / 2;
+    let abuf = g_actx.createBuffer(2, len, freq);
+    let left = abuf.getChannelData(0);
+    let right = abuf.getChannelData(1);
+    for (let i = 0; i < len; ++i) {
+        left[i] = slice[2 * i];
+        right[i] = slice[2 * i + 1];
     }
     asrc.buffer = abuf;
     asrc.connect(g_actx.destination);
@@ -120,6 +130,10 @@ export function putImageData(w, h, data) {
         //data is a Uint8Array, but some browsers need a Uint8ClampedArray
         data = new Uint8ClampedArray(data.buffer, data.byteOffset, data.length);
         let img = new ImageData(data, w, h);
+        if (g_ctx.canvas.width != w || g_ctx.canvas.height != h) {
+            g_ctx.canvas.width = w;
+            g_ctx.canvas.height = h;
+        }
         g_ctx.putImageData(img, 0, 0);
     }
 }
@@ -159,11 +173,41 @@ async function onDocumentLoad() {
         g_model = 0;
     else if (boolURLParamDef(urlParams, 'plus3', false))
         g_model = 2;
+    else if (boolURLParamDef(urlParams, 'tc2048', false))
+        g_model = 3;
+    else if (boolURLParamDef(urlParams, '16k', false))
+        g_model = 4;
+    else if (boolURLParamDef(urlParams, 'plus2', false))
+        g_model = 5;
+    else if (boolURLParamDef(urlParams, 'plus2a', false))
+        g_model = 6;
+    else if (boolURLParamDef(urlParams, 'pentagon', false))
+        g_model = 7;
     else
         g_model = 1;
 
+    let stereo = urlParams.get("stereo");
+    if (stereo)
+        g_stereo = Math.max(0, ["mono", "abc", "acb"].indexOf(stereo.toLowerCase()));
+    g_ym = boolURLParamDef(urlParams, 'ym', false);
+    g_turboSound = boolURLParamDef(urlParams, 'turbosound', false);
+    g_ulaplus = boolURLParamDef(urlParams, 'ulaplus', false);
+    g_snow = boolURLParamDef(urlParams, 'snow', true);
+    let lowPass = parseFloat(urlParams.get("lowpass"));
+    if (lowPass > 0)
+        g_lowPass = lowPass;
+    let highPass = parseFloat(urlParams.get("highpass"));
+    if (highPass >= 0)
+        g_highPass = highPass;
+    let border = urlParams.get("border");
+    if (border) {
+        let size = ["none", "small", "normal", "full"].indexOf(border.toLowerCase());
+        if (size >= 0)
+            g_border = size;
+    }
+
     console.log("Spec model", g_model);
-    g_game = wasm_bindgen.wasm_main(g_model);
+    newGame();
 
     let snapshot = urlParams.get("snapshot");
     if (snapshot) {
@@ -185,7 +229,7 @@ async function onDocumentLoad() {
         await fetch_with_cors_if_needed(tape,
             bytes => {
                 if (bytes) {
-                    if (g_model == 0) {
+                    if (g_model == 0 || g_model == 3 || g_model == 4) {
                         // 48K loading sequence: typìng LOAD ""
                         call_with_delay(2000, 100, [
                             () => wasm_bindgen.wasm_key_down(g_game, 0x63), //J (LOAD)
@@ -203,7 +247,7 @@ async function onDocumentLoad() {
                     } else {
                         // 128K loading sequence: enter in the load menu
                         // +3 loading sequence: same as 128K but a slightly longer delay because of the floppy
-                        call_with_delay(g_model == 3 ? 2000 : 1500, 100, [
+                        call_with_delay(g_model == 2 ? 2000 : 1500, 100, [
                             () => wasm_bindgen.wasm_key_down(g_game, 0x60), //ENTER
                             () => wasm_bindgen.wasm_key_up(g_game, 0x60), //ENTER
                             () => onLoadTape(bytes),
@@ -764,7 +808,19 @@ function handleReset(evt, model) {
     resetTape();
     wasm_bindgen.wasm_drop(g_game);
     g_model = model;
-    g_game = wasm_bindgen.wasm_main(g_model);
+    newGame();
+}
+
+//Creates the emulator for g_model with the options from the url
+function newGame() {
+    g_game = wasm_bindgen.wasm_main(g_model, g_actx.sampleRate);
+    wasm_bindgen.wasm_set_stereo_mode(g_game, g_stereo);
+    wasm_bindgen.wasm_set_turbo_sound(g_game, g_turboSound);
+    wasm_bindgen.wasm_set_psg_chip(g_game, g_ym);
+    wasm_bindgen.wasm_set_border_size(g_game, g_border);
+    wasm_bindgen.wasm_set_ulaplus(g_game, g_ulaplus);
+    wasm_bindgen.wasm_set_snow(g_game, g_snow);
+    wasm_bindgen.wasm_set_speaker_filter(g_game, g_lowPass, g_highPass);
 }
 
 function handleLoadTape(evt) {
@@ -793,7 +849,7 @@ function handleLoadSnapshotSelect(evt) {
 function handleLoadSnapshot(evt) {
     let x = document.createElement("input");
     x.type = "file";
-    x.accept = [".z80", ".rzx", ".zip"];
+    x.accept = [".z80", ".sna", ".rzx", ".zip"];
     x.addEventListener('change', handleLoadSnapshotSelect, false);
     x.click();
 }
@@ -52,6 +52,9 @@ mod color {
             a: 0xff,
         }
     }
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Pixel {
+        Pixel { r, g, b, a: 0xff }
+    }
     const fn lo(c: u8) -> Pixel {
         pixel(false, c)
     }
@@ -72,6 +75,10 @@ impl Gui for JSGui {
 
     const PALETTE: [[Pixel; 8]; 2] = color::PALETTE;
 
+    fn rgb(r: u8, g: u8, b: u8) -> Pixel {
+        color::rgb(r, g, b)
+    }
+
     fn put_image_data(&mut self, w: usize, h: usize, data: &[Self::Pixel]) {
         //Pixel is repr(C) just like [u8;4]
         let ptr = data.as_ptr() as *const u8;
@@ -91,20 +98,26 @@ impl Gui for JSGui {
 }
 
 mod exports {
-    use zxspectrum_raze::Model;
+    use zxspectrum_raze::{BorderSize, Model, PsgChip, SpeakerFilter, StereoMode};
 
     use super::*;
 
     #[wasm_bindgen]
-    pub fn wasm_main(model: i32) -> *mut Game<JSGui> {
+    pub fn wasm_main(model: i32, sample_rate: u32) -> *mut Game<JSGui> {
         let model = match model {
             0 => Model::Spec48k,
             1 => Model::Spec128k,
             2 => Model::Plus3,
+            3 => Model::Tc2048,
+            4 => Model::Spec16k,
+            5 => Model::Plus2,
+            6 => Model::Plus2A,
+            7 => Model::Pentagon,
             _ => Model::Spec128k,
         };
         let _ = console_log::init_with_level(log::Level::Debug);
-        let game = Box::new(Game::new(model, &mut JSGui));
+        let mut game = Box::new(Game::new(model, &mut JSGui));
+        game.set_sample_rate(sample_rate);
         Box::into_raw(game)
     }
     #[wasm_bindgen]
@@ -159,7 +172,17 @@ mod exports {
         let old_game = unsafe { &mut *game };
         log::debug!("snap len {}", data.len());
         match Game::load_snapshot(data, &mut JSGui) {
-            Ok(new_game) => {
+            Ok(mut new_game) => {
+                new_game.set_sample_rate(old_game.sample_rate());
+                new_game.set_stereo_mode(old_game.stereo_mode());
+                new_game.set_turbo_sound(old_game.turbo_sound());
+                new_game.set_border_size(old_game.border_size());
+                new_game.set_ulaplus(old_game.ulaplus());
+                new_game.set_snow(old_game.snow());
+                new_game.set_speaker_filter(old_game.speaker_filter());
+                if let Some(chip) = old_game.psg_chip() {
+                    new_game.set_psg_chip(chip);
+                }
                 *old_game = new_game;
             }
             Err(e) => {
@@ -170,6 +193,11 @@ mod exports {
             Model::Spec48k => 0,
             Model::Spec128k => 1,
             Model::Plus3 => 2,
+            Model::Tc2048 => 3,
+            Model::Spec16k => 4,
+            Model::Plus2 => 5,
+            Model::Plus2A => 6,
+            Model::Pentagon => 7,
         }
     }
     #[wasm_bindgen]
@@ -203,6 +231,55 @@ mod exports {
         game.poke(addr, value);
     }
     #[wasm_bindgen]
+    pub fn wasm_set_border_size(game: *mut Game<JSGui>, size: i32) {
+        let game = unsafe { &mut *game };
+        let size = match size {
+            0 => BorderSize::None,
+            2 => BorderSize::Normal,
+            3 => BorderSize::Full,
+            _ => BorderSize::Small,
+        };
+        game.set_border_size(size);
+    }
+    #[wasm_bindgen]
+    pub fn wasm_set_ulaplus(game: *mut Game<JSGui>, enabled: bool) {
+        let game = unsafe { &mut *game };
+        game.set_ulaplus(enabled);
+    }
+    #[wasm_bindgen]
+    pub fn wasm_set_speaker_filter(game: *mut Game<JSGui>, low_pass: f32, high_pass: f32) {
+        let game = unsafe { &mut *game };
+        game.set_speaker_filter(SpeakerFilter {
+            low_pass,
+            high_pass,
+        });
+    }
+    #[wasm_bindgen]
+    pub fn wasm_set_stereo_mode(game: *mut Game<JSGui>, mode: i32) {
+        let game = unsafe { &mut *game };
+        let mode = match mode {
+            1 => StereoMode::Abc,
+            2 => StereoMode::Acb,
+            _ => StereoMode::Mono,
+        };
+        game.set_stereo_mode(mode);
+    }
+    #[wasm_bindgen]
+    pub fn wasm_set_psg_chip(game: *mut Game<JSGui>, ym: bool) {
+        let game = unsafe { &mut *game };
+        game.set_psg_chip(if ym { PsgChip::Ym2149 } else { PsgChip::Ay8910 });
+    }
+    #[wasm_bindgen]
+    pub fn wasm_set_turbo_sound(game: *mut Game<JSGui>, enabled: bool) {
+        let game = unsafe { &mut *game };
+        game.set_turbo_sound(enabled);
+    }
+    #[wasm_bindgen]
+    pub fn wasm_set_snow(game: *mut Game<JSGui>, enabled: bool) {
+        let game = unsafe { &mut *game };
+        game.set_snow(enabled);
+    }
+    #[wasm_bindgen]
     pub fn wasm_stop_rzx_replay(game: *mut Game<JSGui>) {
         let game = unsafe { &mut *game };
         game.stop_rzx_replay(&mut JSGui);